@@ -0,0 +1,113 @@
+// fixed acpi description table
+//
+// where the power management registers are, how to reset the machine and
+// where the dsdt is. acpi 2.0 added 64 bit versions of most fields. the 64
+// bit dsdt address wins when it is filled in, the io ports only fall back to
+// the generic address versions when the old fields are 0.
+
+use super::{AddressSpace, GenericAddress, Table, u16_at, u32_at, u64_at};
+
+// offsets into the table, header included
+const DSDT: usize = 40;
+const SCI_INTERRUPT: usize = 46;
+const SMI_COMMAND: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const ACPI_DISABLE: usize = 53;
+const PM1A_EVENT: usize = 56;
+const PM1B_EVENT: usize = 60;
+const PM1A_CONTROL: usize = 64;
+const PM1B_CONTROL: usize = 68;
+const PM_TIMER: usize = 76;
+const PM1_CONTROL_LEN: usize = 89;
+const CENTURY: usize = 108;
+const BOOT_ARCH: usize = 109;
+const FLAGS: usize = 112;
+const RESET_REGISTER: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+const X_PM1A_CONTROL: usize = 172;
+const X_PM1B_CONTROL: usize = 184;
+
+// flags
+const RESET_REG_SUP: u32 = 1 << 10;
+
+// ia-pc boot architecture flags
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
+/// The parsed fadt.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub table: Table,
+    /// Physical address of the dsdt.
+    pub dsdt: u64,
+    /// Isa irq of the system control interrupt.
+    pub sci_interrupt: u16,
+    /// Port to write `acpi_enable` to to switch to acpi mode, 0 if there is
+    /// no need.
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// Io ports of the power management blocks, 0 when missing.
+    pub pm1a_event: u32,
+    pub pm1b_event: u32,
+    pub pm1a_control: u32,
+    pub pm1b_control: u32,
+    pub pm1_control_len: u8,
+    pub pm_timer: u32,
+    /// Cmos register holding the century, 0 if there is none.
+    pub century: u8,
+    pub boot_arch: u16,
+    pub flags: u32,
+    /// Writing `reset_value` here resets the machine, if supported.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub fn parse(table: Table) -> Option<Fadt> {
+        let data = table.data;
+        if data.len() < FLAGS + 4 {
+            return None;
+        }
+
+        let x_u64 = |offset: usize| if data.len() >= offset + 8 { u64_at(data, offset) } else { 0 };
+        let x_port = |offset: usize, legacy: u32| match GenericAddress::parse(data, offset) {
+            Some(address) if legacy == 0 && address.space == AddressSpace::SystemIo => address.address as u32,
+            _ => legacy,
+        };
+
+        let flags = u32_at(data, FLAGS);
+        let reset_register = GenericAddress::parse(data, RESET_REGISTER).filter(|_| flags & RESET_REG_SUP != 0);
+
+        Some(Fadt {
+            table,
+            dsdt: Some(x_u64(X_DSDT)).filter(|&dsdt| dsdt != 0).unwrap_or(u32_at(data, DSDT) as u64),
+            sci_interrupt: u16_at(data, SCI_INTERRUPT),
+            smi_command: u32_at(data, SMI_COMMAND),
+            acpi_enable: data[ACPI_ENABLE],
+            acpi_disable: data[ACPI_DISABLE],
+            pm1a_event: u32_at(data, PM1A_EVENT),
+            pm1b_event: u32_at(data, PM1B_EVENT),
+            pm1a_control: x_port(X_PM1A_CONTROL, u32_at(data, PM1A_CONTROL)),
+            pm1b_control: x_port(X_PM1B_CONTROL, u32_at(data, PM1B_CONTROL)),
+            pm1_control_len: data[PM1_CONTROL_LEN],
+            pm_timer: u32_at(data, PM_TIMER),
+            century: data[CENTURY],
+            boot_arch: u16_at(data, BOOT_ARCH),
+            flags,
+            reset_value: data.get(RESET_VALUE).copied().unwrap_or(0),
+            reset_register,
+        })
+    }
+
+    /// Whether there is an 8042 keyboard controller. Firmware before acpi
+    /// 2.0 doesn't say, so assume there is.
+    pub fn has_8042(&self) -> bool {
+        self.table.revision() < 2 || self.boot_arch & BOOT_ARCH_8042 != 0
+    }
+}
+
+/// The fadt, if the firmware has a valid one.
+pub fn get() -> Option<Fadt> {
+    super::find(b"FACP").and_then(Fadt::parse)
+}
@@ -0,0 +1,58 @@
+// hpet description table
+//
+// where the registers of the high precision event timer are and what it
+// can do. the registers themselves say the same, the table is mainly there
+// to find them.
+
+use super::{GenericAddress, Table, u16_at, u32_at};
+
+// offsets into the table, header included
+const EVENT_TIMER_BLOCK_ID: usize = 36;
+const BASE_ADDRESS: usize = 40;
+const HPET_NUMBER: usize = 52;
+const MINIMUM_TICK: usize = 53;
+const SIZE: usize = 56;
+
+/// The parsed hpet table.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub table: Table,
+    pub hardware_revision: u8,
+    pub comparators: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    pub vendor_id: u16,
+    /// Where the registers are, always system memory.
+    pub base_address: GenericAddress,
+    pub number: u8,
+    /// Smallest period in ticks the timers can be programmed with
+    /// periodically without losing interrupts.
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub fn parse(table: Table) -> Option<Hpet> {
+        let data = table.data;
+        if data.len() < SIZE {
+            return None;
+        }
+
+        let id = u32_at(data, EVENT_TIMER_BLOCK_ID);
+        Some(Hpet {
+            table,
+            hardware_revision: id as u8,
+            comparators: ((id >> 8) & 0x1F) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            vendor_id: (id >> 16) as u16,
+            base_address: GenericAddress::parse(data, BASE_ADDRESS)?,
+            number: data[HPET_NUMBER],
+            minimum_tick: u16_at(data, MINIMUM_TICK),
+        })
+    }
+}
+
+/// The hpet table, if the firmware has a valid one.
+pub fn get() -> Option<Hpet> {
+    super::find(b"HPET").and_then(Hpet::parse)
+}
@@ -0,0 +1,140 @@
+// multiple apic description table
+//
+// lists the interrupt controllers: one local apic per cpu, the io apics and
+// how the legacy isa irqs map onto io apic inputs (global system interrupts,
+// gsis) when they don't map one to one.
+
+use super::{Table, u16_at, u32_at, u64_at};
+
+// interrupt source override polarity and trigger mode bits
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b1100;
+const TRIGGER_LEVEL: u16 = 0b1100;
+
+/// The parsed madt.
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub table: Table,
+    /// Physical address of the local apics, after any override entry.
+    pub local_apic_address: u64,
+    /// The machine also has 8259 pics.
+    pub pcat_compat: bool,
+}
+
+/// An entry of the madt.
+#[derive(Debug, Clone, Copy)]
+pub enum Entry {
+    /// A cpu's local apic.
+    LocalApic { processor_id: u8, apic_id: u8, enabled: bool },
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    /// Isa irq `source` is wired to `gsi` instead of the gsi of the same number.
+    InterruptOverride(InterruptOverride),
+    /// A local apic input wired to nmi, for every cpu if `processor_id` is 0xff.
+    LocalApicNmi { processor_id: u8, lint: u8, flags: u16 },
+    /// A 64 bit local apic address replacing the one in the header.
+    LocalApicAddress(u64),
+    /// Any entry type we don't parse.
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    /// Isa interrupts are active high unless overridden.
+    pub fn active_low(&self) -> bool {
+        self.flags & POLARITY_MASK == POLARITY_ACTIVE_LOW
+    }
+
+    /// Isa interrupts are edge triggered unless overridden.
+    pub fn level_triggered(&self) -> bool {
+        self.flags & TRIGGER_MASK == TRIGGER_LEVEL
+    }
+}
+
+impl Madt {
+    pub fn parse(table: Table) -> Option<Madt> {
+        let body = table.body();
+        if body.len() < 8 {
+            return None;
+        }
+
+        let mut madt = Madt {
+            table,
+            local_apic_address: u32_at(body, 0) as u64,
+            pcat_compat: u32_at(body, 4) & 1 != 0,
+        };
+        if let Some(address) = madt.entries().find_map(|entry| match entry {
+            Entry::LocalApicAddress(address) => Some(address),
+            _ => None,
+        }) {
+            madt.local_apic_address = address;
+        }
+        Some(madt)
+    }
+
+    pub fn entries(&self) -> Entries {
+        Entries { data: &self.table.body()[8..] }
+    }
+
+    /// The override for isa irq `irq`, if it has one.
+    pub fn interrupt_override(&self, irq: u8) -> Option<InterruptOverride> {
+        self.entries().find_map(|entry| match entry {
+            Entry::InterruptOverride(o) if o.source == irq => Some(o),
+            _ => None,
+        })
+    }
+}
+
+pub struct Entries {
+    data: &'static [u8],
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let (&typ, &len) = (self.data.first()?, self.data.get(1)?);
+        let len = len as usize;
+        if len < 2 || len > self.data.len() {
+            return None;
+        }
+        let entry = &self.data[..len];
+        self.data = &self.data[len..];
+
+        Some(match (typ, len) {
+            (0, 8..) => Entry::LocalApic {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                enabled: u32_at(entry, 4) & 1 != 0,
+            },
+            (1, 12..) => Entry::IoApic {
+                id: entry[2],
+                address: u32_at(entry, 4),
+                gsi_base: u32_at(entry, 8),
+            },
+            (2, 10..) => Entry::InterruptOverride(InterruptOverride {
+                source: entry[3],
+                gsi: u32_at(entry, 4),
+                flags: u16_at(entry, 8),
+            }),
+            (4, 6..) => Entry::LocalApicNmi {
+                processor_id: entry[2],
+                flags: u16_at(entry, 3),
+                lint: entry[5],
+            },
+            (5, 12..) => Entry::LocalApicAddress(u64_at(entry, 4)),
+            (typ, _) => Entry::Other(typ),
+        })
+    }
+}
+
+/// The madt, if the firmware has a valid one.
+pub fn get() -> Option<Madt> {
+    super::find(b"APIC").and_then(Madt::parse)
+}
@@ -0,0 +1,49 @@
+// pci express memory mapped configuration
+//
+// every pci segment group with its buses' configuration space mapped into
+// memory (ecam) has an entry: bus `b`, device `d`, function `f` are at
+// `base + (b - start_bus) << 20 | d << 15 | f << 12`.
+
+use super::{Table, u16_at, u64_at};
+
+// the body starts with 8 reserved bytes
+const ENTRIES: usize = 44;
+const ENTRY_SIZE: usize = 16;
+
+/// The configuration space of a range of buses.
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    /// Physical address of bus `start_bus`.
+    pub base: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// The parsed mcfg.
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+    pub table: Table,
+}
+
+impl Mcfg {
+    pub fn parse(table: Table) -> Option<Mcfg> {
+        (table.data.len() >= ENTRIES).then_some(Mcfg { table })
+    }
+
+    pub fn allocations(&self) -> impl Iterator<Item = Allocation> {
+        self.table.data[ENTRIES..]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| Allocation {
+                base: u64_at(entry, 0),
+                segment: u16_at(entry, 8),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+    }
+}
+
+/// The mcfg, if the firmware has a valid one.
+pub fn get() -> Option<Mcfg> {
+    super::find(b"MCFG").and_then(Mcfg::parse)
+}
@@ -0,0 +1,337 @@
+// acpi tables
+//
+// the firmware describes the machine in tables rooted at the rsdp. grub
+// usually hands us a copy of the rsdp in a multiboot2 tag, otherwise it is
+// somewhere in the first KiB of the ebda or in the bios area below 1 MiB.
+// the rsdp points at the rsdt, which lists every other table with 32 bit
+// pointers, or from acpi 2.0 on at the xsdt, which does the same with 64 bit
+// ones. every table starts with the same 36 byte header and is checked
+// against its checksum before we look at it. tables are read through the
+// physical map. some firmware puts them above the end of ram, where the
+// physical map doesn't reach: those are mapped in on first use and stay
+// mapped.
+//
+// the typed tables:
+//
+//   madt   interrupt controllers
+//   fadt   power management registers and the dsdt
+//   hpet   the high precision event timer
+//   mcfg   pci express configuration space
+
+use core::{slice, str};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::PhysAddr;
+use crate::{
+    memory::{self, PHYS_OFFSET, mmio, pat::CacheMode, physmap},
+    multiboot::{self, MultibootInfo},
+};
+
+pub mod madt; // multiple apic description table
+pub mod fadt; // fixed acpi description table
+pub mod hpet; // hpet description table
+pub mod mcfg; // pci express memory mapped configuration
+pub mod power; // soft power off
+
+// size of the header every table starts with
+const HEADER_SIZE: usize = 36;
+
+// acpi 1.0 rsdp and the acpi 2.0 extension of it
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+// where the real mode segment of the ebda is kept
+const EBDA_POINTER: u64 = 0x40E;
+
+// the bios area the rsdp may be in
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+// no table is bigger than this, a longer one is corrupt
+const MAX_TABLE_SIZE: usize = 1024 * 1024;
+
+// most mappings of tables outside the physical map
+const MAX_WINDOWS: usize = 16;
+
+const PAGE_SIZE: u64 = 4096;
+
+static RSDP: OnceCell<Rsdp> = OnceCell::uninit();
+static ROOT: OnceCell<Table> = OnceCell::uninit();
+
+// tables outside the physical map: whole pages from `phys`, mapped at `virt`
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    phys: u64,
+    size: u64,
+    virt: *const u8,
+}
+
+unsafe impl Send for Window {}
+
+static WINDOWS: Mutex<[Option<Window>; MAX_WINDOWS]> = Mutex::new([None; MAX_WINDOWS]);
+
+/// The root system description pointer.
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub phys: PhysAddr,
+    /// 0 for acpi 1.0, 2 from acpi 2.0 on.
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub rsdt: u32,
+    /// Only from acpi 2.0 on.
+    pub xsdt: Option<u64>,
+}
+
+impl Rsdp {
+    // checks the signature and checksums of the rsdp at `phys`
+    fn load(phys: u64) -> Option<Rsdp> {
+        let v1 = phys_bytes(phys, RSDP_V1_SIZE)?;
+        if &v1[0..8] != b"RSD PTR " || !checksum(v1) {
+            return None;
+        }
+
+        // acpi 2.0 adds the xsdt address, covered by its own checksum
+        let revision = v1[15];
+        let xsdt = match phys_bytes(phys, RSDP_V2_SIZE) {
+            Some(v2) if revision >= 2 && checksum(v2) => Some(u64_at(v2, 24)).filter(|&xsdt| xsdt != 0),
+            _ => None,
+        };
+
+        Some(Rsdp {
+            phys: PhysAddr::new(phys),
+            revision,
+            oem_id: v1[9..15].try_into().unwrap(),
+            rsdt: u32_at(v1, 16),
+            xsdt,
+        })
+    }
+
+    pub fn oem_id(&self) -> &str {
+        str::from_utf8(&self.oem_id).unwrap_or("??????").trim_end()
+    }
+}
+
+/// A system description table.
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub phys: PhysAddr,
+    /// The whole table, header included.
+    pub data: &'static [u8],
+}
+
+impl Table {
+    // checks the length and checksum of the table at `phys`
+    fn load(phys: u64) -> Option<Table> {
+        let header = phys_bytes(phys, HEADER_SIZE)?;
+        let len = u32_at(header, 4) as usize;
+        if !(HEADER_SIZE..=MAX_TABLE_SIZE).contains(&len) {
+            return None;
+        }
+        let data = phys_bytes(phys, len)?;
+        checksum(data).then_some(Table { phys: PhysAddr::new(phys), data })
+    }
+
+    pub fn signature(&self) -> &'static str {
+        str::from_utf8(&self.data[0..4]).unwrap_or("????")
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.len() == HEADER_SIZE
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.data[8]
+    }
+
+    pub fn oem_id(&self) -> &'static str {
+        str::from_utf8(&self.data[10..16]).unwrap_or("??????").trim_end()
+    }
+
+    pub fn oem_table_id(&self) -> &'static str {
+        str::from_utf8(&self.data[16..24]).unwrap_or("????????").trim_end()
+    }
+
+    pub fn oem_revision(&self) -> u32 {
+        u32_at(self.data, 24)
+    }
+
+    /// The table contents after the header.
+    pub fn body(&self) -> &'static [u8] {
+        &self.data[HEADER_SIZE..]
+    }
+}
+
+/// A register location in a table, the acpi generic address structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    // the 12 byte structure at `offset` of `data`, if it is there at all
+    pub(crate) fn parse(data: &[u8], offset: usize) -> Option<GenericAddress> {
+        let bytes = data.get(offset..offset + 12)?;
+        let space = match bytes[0] {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+        let address = u64_at(bytes, 4);
+        (address != 0).then_some(GenericAddress {
+            space,
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address,
+        })
+    }
+}
+
+/// Looks for the rsdp: grub's copy from the multiboot2 acpi tags first, then
+/// the first KiB of the ebda and the bios area.
+///
+/// Only low memory and the multiboot2 info are looked at, so the boot page
+/// tables are enough. A copy beyond them is skipped, nothing can be mapped
+/// this early.
+pub fn find_rsdp(info: &MultibootInfo) -> Option<PhysAddr> {
+    let tagged = info
+        .rsdp()
+        .map(|copy| copy.as_ptr() as u64 - PHYS_OFFSET)
+        .filter(|&phys| Rsdp::load(phys).is_some());
+    if let Some(phys) = tagged {
+        return Some(PhysAddr::new(phys));
+    }
+
+    let ebda = phys_bytes(EBDA_POINTER, 2).map(|segment| (u16_at(segment, 0) as u64) << 4);
+    let ebda = ebda.filter(|&ebda| ebda != 0).map(|ebda| (ebda, ebda + 1024));
+    ebda.into_iter()
+        .chain(core::iter::once((BIOS_AREA_START, BIOS_AREA_END)))
+        .flat_map(|(start, end)| (start..end).step_by(16))
+        .find(|&phys| Rsdp::load(phys).is_some())
+        .map(PhysAddr::new)
+}
+
+/// Loads the root table the rsdp found at boot points at. Returns `false`
+/// if there is no usable acpi.
+///
+/// Call once the physical map is set up.
+pub fn init() -> bool {
+    let rsdp = multiboot::boot_info()
+        .and_then(|boot_info| boot_info.rsdp_addr.into_option())
+        .and_then(Rsdp::load);
+    let Some(rsdp) = rsdp else {
+        return false;
+    };
+
+    let root = rsdp.xsdt.and_then(Table::load).or_else(|| Table::load(rsdp.rsdt as u64));
+    let Some(root) = root else {
+        return false;
+    };
+    let _ = RSDP.try_init_once(|| rsdp);
+    ROOT.try_init_once(|| root).is_ok()
+}
+
+/// The rsdp, once `init` has found a root table.
+pub fn rsdp() -> Option<&'static Rsdp> {
+    RSDP.get()
+}
+
+/// The rsdt or xsdt.
+pub fn root() -> Option<&'static Table> {
+    ROOT.get()
+}
+
+/// Every valid table the root table lists.
+pub fn tables() -> impl Iterator<Item = Table> {
+    let (entries, entry_size) = match ROOT.get() {
+        Some(root) if &root.data[0..4] == b"XSDT" => (root.body(), 8),
+        Some(root) => (root.body(), 4),
+        None => (&[][..], 4),
+    };
+    entries
+        .chunks_exact(entry_size)
+        .map(move |entry| match entry_size {
+            8 => u64_at(entry, 0),
+            _ => u32_at(entry, 0) as u64,
+        })
+        .filter_map(Table::load)
+}
+
+/// The first valid table with the given signature.
+pub fn find(signature: &[u8; 4]) -> Option<Table> {
+    tables().find(|table| table.data[0..4] == *signature)
+}
+
+/// The dsdt, which the fadt points at rather than the root table.
+pub fn dsdt() -> Option<Table> {
+    fadt::get().and_then(|fadt| Table::load(fadt.dsdt))
+}
+
+// `len` bytes at `phys`, through the physical map if it covers them
+fn phys_bytes(phys: u64, len: usize) -> Option<&'static [u8]> {
+    let end = phys.checked_add(len as u64)?;
+    if phys == 0 {
+        return None;
+    }
+    if end <= physmap::end() {
+        return Some(unsafe { slice::from_raw_parts((PHYS_OFFSET + phys) as *const u8, len) });
+    }
+    // at boot, before there are page tables to map it with
+    if !memory::initialized() {
+        return None;
+    }
+
+    let mut windows = WINDOWS.lock();
+    let window = windows
+        .iter()
+        .flatten()
+        .find(|w| w.phys <= phys && end <= w.phys + w.size)
+        .copied();
+    let window = match window {
+        Some(window) => window,
+        None => {
+            let slot = windows.iter().position(Option::is_none)?;
+            let base = phys & !(PAGE_SIZE - 1);
+            let size = (end - base).next_multiple_of(PAGE_SIZE);
+            let mapping = mmio::map_mmio_with(PhysAddr::new(base), size as usize, CacheMode::WriteBack).ok()?;
+            let window = Window { phys: base, size, virt: mapping.as_ptr() };
+            windows[slot] = Some(window);
+            window
+        }
+    };
+    Some(unsafe { slice::from_raw_parts(window.virt.add((phys - window.phys) as usize), len) })
+}
+
+// all bytes of a table add up to 0
+fn checksum(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+pub(crate) fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+pub(crate) fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,163 @@
+// acpi soft power off and reset
+//
+// powering off means writing the sleep type of state s5 with the sleep
+// enable bit to the pm1 control registers from the fadt. the sleep types are
+// in the `\_S5` package of the dsdt, which is aml bytecode. we don't
+// interpret aml, the package is found with a byte scan:
+//
+//     NameOp '_S5_' PackageOp PkgLength NumElements SLP_TYPa SLP_TYPb ...
+//
+// where each sleep type is a ByteConst (0x0a and a byte) or a ZeroOp/OneOp.
+//
+// resetting is simpler: the fadt may name a reset register and the value to
+// write to it.
+
+use core::ptr;
+use x86_64::{PhysAddr, instructions::port::Port};
+use crate::memory::mmio;
+use super::{AddressSpace, Table, fadt::{self, Fadt}};
+
+// aml opcodes
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const BYTE_PREFIX: u8 = 0x0A;
+
+// pm1 control register bits
+const SCI_EN: u16 = 1 << 0;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+
+// pci configuration space access ports
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+
+// how often to poll for acpi mode after asking for it
+const ACPI_ENABLE_POLLS: usize = 1_000_000;
+
+/// The values for the pm1a and pm1b control registers' sleep type fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// The sleep types of state s5 (soft off), from the dsdt or an ssdt.
+pub fn s5() -> Option<SleepType> {
+    super::dsdt()
+        .into_iter()
+        .chain(super::tables().filter(|table| table.signature() == "SSDT"))
+        .find_map(|table| find_sleep_type(&table, b"_S5_"))
+}
+
+// scans the aml of `table` for the package called `name`
+fn find_sleep_type(table: &Table, name: &[u8; 4]) -> Option<SleepType> {
+    let aml = table.body();
+    aml.windows(4)
+        .enumerate()
+        .filter(|&(i, window)| window == name && i > 0)
+        // a name definition, possibly with a root prefix in front of the name
+        .filter(|&(i, _)| aml[i - 1] == NAME_OP || (aml[i - 1] == b'\\' && i > 1 && aml[i - 2] == NAME_OP))
+        .find_map(|(i, _)| parse_package(aml.get(i + 4..)?))
+}
+
+// the first two integers of the package at the start of `aml`
+fn parse_package(aml: &[u8]) -> Option<SleepType> {
+    if *aml.first()? != PACKAGE_OP {
+        return None;
+    }
+    // the top two bits of the first pkglength byte say how many follow
+    let length_bytes = (*aml.get(1)? >> 6) as usize + 1;
+    // skip the opcode, pkglength and the element count
+    let mut rest = aml.get(1 + length_bytes + 1..)?;
+
+    let mut next = || -> Option<u8> {
+        let (value, len) = match *rest.first()? {
+            ZERO_OP => (0, 1),
+            ONE_OP => (1, 1),
+            BYTE_PREFIX => (*rest.get(1)?, 2),
+            _ => return None,
+        };
+        rest = &rest[len..];
+        Some(value)
+    };
+    let a = next()?;
+    let b = next().unwrap_or(0);
+    Some(SleepType { a, b })
+}
+
+/// Powers the machine off through acpi. Only returns if that didn't work.
+pub fn power_off() {
+    let (Some(fadt), Some(sleep_type)) = (fadt::get(), s5()) else {
+        return;
+    };
+    if fadt.pm1a_control == 0 || !enable_acpi_mode(&fadt) {
+        return;
+    }
+
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        write_sleep(fadt.pm1a_control, sleep_type.a);
+        if fadt.pm1b_control != 0 {
+            write_sleep(fadt.pm1b_control, sleep_type.b);
+        }
+    }
+}
+
+// hands power management from the firmware to the os, if it isn't already
+fn enable_acpi_mode(fadt: &Fadt) -> bool {
+    let mut control = Port::<u16>::new(fadt.pm1a_control as u16);
+    if unsafe { control.read() } & SCI_EN != 0 {
+        return true;
+    }
+    // no way to switch means hardware-reduced acpi or always in acpi mode
+    if fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return true;
+    }
+
+    unsafe { Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable) };
+    (0..ACPI_ENABLE_POLLS).any(|_| unsafe { control.read() } & SCI_EN != 0)
+}
+
+unsafe fn write_sleep(port: u32, sleep_type: u8) {
+    let mut control = Port::<u16>::new(port as u16);
+    unsafe {
+        let value = control.read() & !(0b111 << SLP_TYP_SHIFT);
+        control.write(value | (sleep_type as u16) << SLP_TYP_SHIFT | SLP_EN);
+    }
+}
+
+/// Writes the reset value to the fadt reset register. Returns `false` if
+/// there is no reset register, or it is somewhere we can't write to.
+pub fn reset() -> bool {
+    let Some(fadt) = fadt::get() else {
+        return false;
+    };
+    let Some(register) = fadt.reset_register else {
+        return false;
+    };
+
+    let address = register.address;
+    match register.space {
+        AddressSpace::SystemIo => unsafe { Port::<u8>::new(address as u16).write(fadt.reset_value) },
+        AddressSpace::SystemMemory => {
+            let Ok(mapping) = mmio::map_mmio(PhysAddr::new(address), 1) else {
+                return false;
+            };
+            unsafe { ptr::write_volatile(mapping.as_mut_ptr(), fadt.reset_value) };
+        }
+        // bus 0, device in bits 32-47, function in 16-31, offset in 0-15
+        AddressSpace::PciConfig => {
+            let device = (address >> 32) as u32 & 0x1F;
+            let function = (address >> 16) as u32 & 0x7;
+            let offset = address as u32 & 0xFF;
+            unsafe {
+                Port::<u32>::new(PCI_CONFIG_ADDRESS).write(1 << 31 | device << 11 | function << 8 | (offset & 0xFC));
+                Port::<u8>::new(PCI_CONFIG_DATA + (offset & 3) as u16).write(fadt.reset_value);
+            }
+        }
+        AddressSpace::Other(_) => return false,
+    }
+    true
+}
@@ -0,0 +1,324 @@
+// heap corruption detection
+//
+// with `heap_debug=on` on the command line, every allocation gets a header
+// and red zones on both sides:
+//
+//     | header | red zone | data | red zone |
+//
+// the red zones are filled with RED and checked when the allocation is freed.
+// freed memory is filled with POISON and parked in a quarantine for a while
+// before it really goes back to the heap, and the poison is checked again on
+// the way out. a write through a dangling pointer then shows up as broken
+// poison instead of a random page fault much later. `check` goes over every
+// live and quarantined allocation on demand.
+//
+// headers of live allocations are linked into a list, so the scan can find
+// them without any memory of its own.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr,
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+use crate::println;
+
+// bytes around each allocation
+const RED_ZONE: usize = 16;
+
+// freed allocations held back before they are reused
+const QUARANTINE: usize = 64;
+
+const RED: u8 = 0xFD;
+const POISON: u8 = 0x6B;
+
+const LIVE_MAGIC: u32 = 0x4B4F_5321;
+const FREED_MAGIC: u32 = 0x4B4F_5344;
+
+const HEADER_SIZE: usize = size_of::<Header>();
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[repr(C)]
+struct Header {
+    magic: u32,
+    align: u32,
+    size: usize,
+    prev: *mut Header,
+    next: *mut Header,
+}
+
+impl Header {
+    // offset of the data from the header
+    fn front(align: usize) -> usize {
+        (HEADER_SIZE + RED_ZONE).next_multiple_of(align)
+    }
+
+    fn inner_layout(size: usize, align: usize) -> Layout {
+        let align = align.max(align_of::<Header>());
+        Layout::from_size_align(Self::front(align) + size + RED_ZONE, align).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        Self::inner_layout(self.size, self.align as usize)
+    }
+
+    fn data(&mut self) -> *mut u8 {
+        let front = Self::front(self.layout().align());
+        unsafe { (self as *mut Header as *mut u8).add(front) }
+    }
+
+    // everything behind the header: red zones and data
+    fn body(&mut self) -> &mut [u8] {
+        let len = self.layout().size() - HEADER_SIZE;
+        unsafe { slice::from_raw_parts_mut((self as *mut Header as *mut u8).add(HEADER_SIZE), len) }
+    }
+
+    // the red zones before and after the data
+    fn red_zones(&mut self) -> (&mut [u8], &mut [u8]) {
+        let front = Self::front(self.layout().align()) - HEADER_SIZE;
+        let size = self.size;
+        let body = self.body();
+        let (before, rest) = body.split_at_mut(front);
+        (before, &mut rest[size..])
+    }
+}
+
+/// What went wrong with an allocation.
+#[derive(Debug, Clone, Copy)]
+pub enum Corruption {
+    /// The header was overwritten, or the pointer never came from the heap.
+    Header,
+    /// A write before the data, this many bytes in front of it.
+    Underflow(usize),
+    /// A write past the data, this many bytes after its end.
+    Overflow(usize),
+    /// A write after free, at this offset into the data.
+    UseAfterFree(isize),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckReport {
+    pub live: usize,
+    pub quarantined: usize,
+    pub corrupted: usize,
+}
+
+struct State {
+    live: *mut Header,
+    quarantine: [*mut Header; QUARANTINE],
+    next: usize, // oldest quarantine slot
+}
+
+// only touched with the lock held
+unsafe impl Send for State {}
+
+impl State {
+    fn link(&mut self, header: *mut Header) {
+        unsafe {
+            (*header).prev = ptr::null_mut();
+            (*header).next = self.live;
+            if !self.live.is_null() {
+                (*self.live).prev = header;
+            }
+        }
+        self.live = header;
+    }
+
+    fn unlink(&mut self, header: *mut Header) {
+        unsafe {
+            let (prev, next) = ((*header).prev, (*header).next);
+            if prev.is_null() {
+                self.live = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+
+    // parks a freed allocation and returns the one it pushes out
+    fn quarantine(&mut self, header: *mut Header) -> *mut Header {
+        let oldest = self.quarantine[self.next];
+        self.quarantine[self.next] = header;
+        self.next = (self.next + 1) % QUARANTINE;
+        oldest
+    }
+}
+
+fn check_live(header: &mut Header) -> Result<(), Corruption> {
+    if header.magic != LIVE_MAGIC {
+        return Err(Corruption::Header);
+    }
+    let (before, after) = header.red_zones();
+    if let Some(i) = before.iter().position(|&b| b != RED) {
+        return Err(Corruption::Underflow(before.len() - i));
+    }
+    if let Some(i) = after.iter().rposition(|&b| b != RED) {
+        return Err(Corruption::Overflow(i + 1));
+    }
+    Ok(())
+}
+
+fn check_freed(header: &mut Header) -> Result<(), Corruption> {
+    if header.magic != FREED_MAGIC {
+        return Err(Corruption::Header);
+    }
+    let front = Header::front(header.layout().align()) - HEADER_SIZE;
+    match header.body().iter().position(|&b| b != POISON) {
+        Some(i) => Err(Corruption::UseAfterFree(i as isize - front as isize)),
+        None => Ok(()),
+    }
+}
+
+/// Puts red zones around allocations and poisons freed ones while heap
+/// debugging is on, otherwise passes everything straight through.
+pub struct Guarded<A: 'static> {
+    inner: &'static A,
+    state: Mutex<State>,
+}
+
+impl<A: GlobalAlloc> Guarded<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        Guarded {
+            inner,
+            state: Mutex::new(State {
+                live: ptr::null_mut(),
+                quarantine: [ptr::null_mut(); QUARANTINE],
+                next: 0,
+            }),
+        }
+    }
+
+    // allocates through `alloc`, which gets the layout including red zones
+    pub(super) fn alloc_with(&self, layout: Layout, alloc: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+        if !enabled() {
+            return alloc(layout);
+        }
+
+        let header = alloc(Header::inner_layout(layout.size(), layout.align())) as *mut Header;
+        if header.is_null() {
+            return ptr::null_mut();
+        }
+
+        unsafe {
+            header.write(Header {
+                magic: LIVE_MAGIC,
+                align: layout.align() as u32,
+                size: layout.size(),
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+            });
+            (*header).body().fill(RED);
+            self.state.lock().link(header);
+            (*header).data()
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Guarded<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_with(layout, |layout| unsafe { self.inner.alloc(layout) })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !enabled() {
+            return unsafe { self.inner.dealloc(ptr, layout) };
+        }
+
+        let header = unsafe { ptr.sub(Header::front(layout.align().max(align_of::<Header>()))) } as *mut Header;
+        let header = unsafe { &mut *header };
+        if let Err(corruption) = check_live(header) {
+            panic!("heap: {:?} in allocation at {:#x} ({} bytes), found on free", corruption, ptr as usize, layout.size());
+        }
+
+        let mut state = self.state.lock();
+        state.unlink(header);
+        header.magic = FREED_MAGIC;
+        header.body().fill(POISON);
+
+        let oldest = state.quarantine(header);
+        drop(state);
+        if oldest.is_null() {
+            return;
+        }
+
+        unsafe { self.release(oldest) };
+    }
+}
+
+impl<A: GlobalAlloc> Guarded<A> {
+    // checks the poison of a quarantined allocation and frees it for real
+    unsafe fn release(&self, header: *mut Header) {
+        let header = unsafe { &mut *header };
+        if let Err(corruption) = check_freed(header) {
+            panic!("heap: {:?} in freed allocation at {:#x} ({} bytes)", corruption, header.data() as usize, header.size);
+        }
+        let layout = header.layout();
+        unsafe { self.inner.dealloc(header as *mut Header as *mut u8, layout) };
+    }
+
+    // frees everything in the quarantine and returns the bytes it held
+    pub(super) fn flush(&self) -> usize {
+        let quarantine = {
+            let mut state = self.state.lock();
+            state.next = 0;
+            core::mem::replace(&mut state.quarantine, [ptr::null_mut(); QUARANTINE])
+        };
+
+        let mut freed = 0;
+        for header in quarantine.into_iter().filter(|h| !h.is_null()) {
+            freed += unsafe { (*header).layout().size() };
+            unsafe { self.release(header) };
+        }
+        freed
+    }
+}
+
+impl<A> Guarded<A> {
+    /// Checks every live and quarantined allocation, printing each broken
+    /// one.
+    pub fn check(&self) -> CheckReport {
+        let state = self.state.lock();
+        let mut report = CheckReport::default();
+
+        let mut header = state.live;
+        while let Some(h) = unsafe { header.as_mut() } {
+            report.live += 1;
+            if let Err(corruption) = check_live(h) {
+                report.corrupted += 1;
+                println!("heap: {:?} in allocation at {:#x} ({} bytes)", corruption, h.data() as usize, h.size);
+                // a broken header can't be trusted to point anywhere
+                if matches!(corruption, Corruption::Header) {
+                    break;
+                }
+            }
+            header = h.next;
+        }
+
+        for &header in state.quarantine.iter().filter(|h| !h.is_null()) {
+            let h = unsafe { &mut *header };
+            report.quarantined += 1;
+            if let Err(corruption) = check_freed(h) {
+                report.corrupted += 1;
+                println!("heap: {:?} in freed allocation at {:#x} ({} bytes)", corruption, h.data() as usize, h.size);
+            }
+        }
+        report
+    }
+}
+
+/// Turns heap debugging on if `heap_debug=on` is on the command line. Must
+/// run before the first allocation.
+pub fn init() {
+    if crate::cmdline::flag("heap_debug") == Some(true) {
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
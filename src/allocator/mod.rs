@@ -0,0 +1,331 @@
+use x86_64::{
+    VirtAddr,
+    structures::paging::{
+        PageTableFlags,
+        Size4KiB,
+        mapper::MapToError,
+    }
+};
+use linked_list_allocator::Heap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem,
+    ptr::{self, NonNull},
+};
+use alloc::{boxed::Box, vec::Vec};
+use spin::Mutex;
+use crate::{cmdline, memory::{self, layout}};
+use slab::SlabAllocator;
+use oom::Largest;
+use track::Tracking;
+use guard::Guarded;
+
+pub mod slab; // size class caches
+pub mod oom; // out of memory policy
+pub mod track; // allocation tracking
+pub mod guard; // red zones and poisoning
+
+static ALLOCATOR: KernelHeap = KernelHeap::new();
+
+// red zones and poisoning when `heap_debug=on`
+static GUARDED: Guarded<KernelHeap> = Guarded::new(&ALLOCATOR);
+
+// records allocations when `heap_track=on`
+#[global_allocator]
+static GLOBAL: Tracking<Guarded<KernelHeap>> = Tracking::new(&GUARDED);
+
+pub const HEAP_START: usize = layout::HEAP.start as usize;
+pub const HEAP_SIZE: usize = 1000 * 1024; // 1000 KiB, default initial size
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB, default limit
+
+// the heap grows by at least this much at a time
+const GROW_SIZE: usize = 256 * 1024;
+
+// most pieces the heap can be split into
+const MAX_SEGMENTS: usize = 64;
+
+const PAGE_SIZE: usize = 4096;
+
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+// the heap starts out as one segment of `heap=` bytes. when that runs out,
+// another segment is mapped behind the last one, up to `heap_max=` bytes in
+// total. every segment is its own linked list heap, so a grown segment can be
+// unmapped and its frames returned once nothing in it is used anymore. one
+// empty segment is kept mapped, so an allocation that comes and goes right at
+// the edge doesn't map and unmap a segment every time; it is released when
+// a second one empties. memory is only ever returned in whole segments, see
+// `release`.
+struct Segments {
+    segments: [Option<Heap>; MAX_SEGMENTS], // the first one is never released
+    next: usize,                            // where the next segment goes
+    mapped: usize,                          // bytes mapped in all segments
+    max: usize,                             // limit for `mapped`
+    largest: Largest,                       // for the out of memory report
+}
+
+impl Segments {
+    const fn new() -> Self {
+        Segments {
+            segments: [const { None }; MAX_SEGMENTS],
+            next: HEAP_START,
+            mapped: 0,
+            max: HEAP_MAX_SIZE,
+            largest: Largest::new(),
+        }
+    }
+
+    // maps a new segment of at least `size` bytes
+    fn add_segment(&mut self, size: usize) -> Result<(), MapToError<Size4KiB>> {
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let slot = self.segments.iter().position(Option::is_none);
+
+        // out of slots or over the limit is as good as out of frames
+        let Some(slot) = slot else {
+            return Err(MapToError::FrameAllocationFailed);
+        };
+        if self.mapped + size > self.max || self.next + size > layout::HEAP.end() as usize {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+
+        memory::map_range(VirtAddr::new(self.next as u64), size as u64, HEAP_FLAGS)?;
+        self.segments[slot] = Some(unsafe { Heap::new(self.next, size) });
+        self.next += size;
+        self.mapped += size;
+        Ok(())
+    }
+
+    fn try_alloc(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        self.segments
+            .iter_mut()
+            .flatten()
+            .find_map(|heap| heap.allocate_first_fit(layout).ok())
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Some(ptr) = self.try_alloc(layout) {
+            return ptr.as_ptr();
+        }
+
+        // room for the allocation even after aligning it
+        let needed = layout.size() + layout.align();
+        if self.add_segment(needed.max(GROW_SIZE)).is_err() {
+            return ptr::null_mut();
+        }
+        self.try_alloc(layout).map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let addr = ptr as usize;
+        let slot = self.segments
+            .iter()
+            .position(|heap| heap.as_ref().is_some_and(|h| (h.bottom()..h.top()).contains(&addr)))
+            .expect("heap: freeing a pointer that is not on the heap");
+
+        let heap = self.segments[slot].as_mut().unwrap();
+        unsafe { heap.deallocate(NonNull::new_unchecked(ptr), layout) };
+
+        if slot != 0 && heap.used() == 0 && let Some(spare) = self.spare(slot) {
+            self.release(spare);
+        }
+    }
+
+    // another grown segment that is empty, besides `slot`
+    fn spare(&self, slot: usize) -> Option<usize> {
+        self.segments
+            .iter()
+            .enumerate()
+            .skip(1)
+            .find(|&(i, heap)| i != slot && heap.as_ref().is_some_and(|h| h.used() == 0))
+            .map(|(i, _)| i)
+    }
+
+    // unmaps an unused segment and returns its frames
+    //
+    // this is the only way heap memory goes back to the frame allocator, a
+    // whole grown segment (at least GROW_SIZE) at a time. free pages inside
+    // a segment that is still in use stay mapped: `linked_list_allocator`
+    // keeps its list of free holes in the free memory itself, so unmapping
+    // a hole or the free tail would cut the list. one live allocation keeps
+    // its whole segment mapped, and the first segment is never released.
+    fn release(&mut self, slot: usize) {
+        let heap = self.segments[slot].take().unwrap();
+        let (bottom, size) = (heap.bottom(), heap.size());
+
+        unsafe { memory::unmap_range(VirtAddr::new(bottom as u64), size as u64) };
+        self.mapped -= size;
+        if bottom + size == self.next {
+            self.next = bottom;
+        }
+    }
+
+    fn used(&self) -> usize {
+        self.segments.iter().flatten().map(Heap::used).sum()
+    }
+}
+
+/// The kernel heap: size class caches for small allocations, in front of a
+/// set of linked list heaps that grows on demand.
+///
+/// Slabs taken by the caches are only given back when the heap runs out, so
+/// until then a segment holding one stays mapped.
+pub struct KernelHeap {
+    slabs: SlabAllocator,
+    segments: Mutex<Segments>,
+}
+
+impl KernelHeap {
+    const fn new() -> Self {
+        KernelHeap {
+            slabs: SlabAllocator::new(),
+            segments: Mutex::new(Segments::new()),
+        }
+    }
+
+    // allocates, growing the heap if needed
+    fn alloc_inner(&self, layout: Layout) -> *mut u8 {
+        match slab::class_for(layout) {
+            Some(class) => self.slabs.alloc(class, |slab| self.segments.lock().alloc(slab)),
+            None => {
+                let mut segments = self.segments.lock();
+                let ptr = segments.alloc(layout);
+                if !ptr.is_null() {
+                    segments.largest.track(ptr as usize, layout.size());
+                }
+                ptr
+            }
+        }
+    }
+
+    // allocates, running the reclaimers once if the heap can't grow
+    fn alloc_or_reclaim(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.alloc_inner(layout);
+        if ptr.is_null() && oom::reclaim() > 0 {
+            return self.alloc_inner(layout);
+        }
+        ptr
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    // null when even reclaiming didn't help, callers that can't cope end up
+    // in `oom::out_of_memory`
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_or_reclaim(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match slab::class_for(layout) {
+            Some(class) => unsafe { self.slabs.dealloc(class, ptr) },
+            None => {
+                let mut segments = self.segments.lock();
+                segments.largest.untrack(ptr as usize);
+                unsafe { segments.dealloc(ptr, layout) }
+            }
+        }
+    }
+}
+
+// gives completely free slabs back to the linked list heap
+fn reclaim_slabs() -> usize {
+    let released = ALLOCATOR.slabs.reclaim(|slab| unsafe {
+        ALLOCATOR.segments.lock().dealloc(slab, slab::slab_layout())
+    });
+    released * slab::SLAB_SIZE
+}
+
+/// Initialize heap using this allocator
+///
+/// The initial size defaults to `HEAP_SIZE` and can be set with `heap=` on
+/// the command line, e.g. `heap=4M`. The heap grows on demand up to
+/// `HEAP_MAX_SIZE`, or `heap_max=`.
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    guard::init();
+    let heap_size = cmdline::size("heap").unwrap_or(HEAP_SIZE).max(PAGE_SIZE);
+
+    let mut segments = ALLOCATOR.segments.lock();
+    segments.max = cmdline::size("heap_max")
+        .unwrap_or(HEAP_MAX_SIZE)
+        .max(heap_size);
+    segments.add_segment(heap_size)?;
+
+    oom::register_reclaimer("heap quarantine", || GUARDED.flush());
+    oom::register_reclaimer("slab caches", reclaim_slabs);
+    drop(segments);
+
+    track::init();
+    Ok(())
+} // fn init_heap
+
+/// Allocates `layout` like the global allocator, but returns `None` instead
+/// of halting when the heap is out of memory.
+#[inline(never)]
+pub fn try_alloc(layout: Layout) -> Option<NonNull<u8>> {
+    if layout.size() == 0 {
+        return NonNull::new(layout.align() as *mut u8);
+    }
+    let ptr = GUARDED.alloc_with(layout, |layout| ALLOCATOR.alloc_or_reclaim(layout));
+    // never inlined, so the first return address is the caller's
+    track::record(ptr, layout.size(), 0);
+    NonNull::new(ptr)
+}
+
+/// Boxes `value`, or hands it back if the heap is out of memory.
+pub fn try_box<T>(value: T) -> Result<Box<T>, T> {
+    let Some(ptr) = try_alloc(Layout::new::<T>()) else {
+        return Err(value);
+    };
+    let ptr = ptr.cast::<T>().as_ptr();
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// An empty vector with room for at least `capacity` elements, or `None` if
+/// the heap is out of memory.
+pub fn try_vec<T>(capacity: usize) -> Option<Vec<T>> {
+    if capacity == 0 || mem::size_of::<T>() == 0 {
+        return Some(Vec::new());
+    }
+    let layout = Layout::array::<T>(capacity).ok()?;
+    let ptr = try_alloc(layout)?.cast::<T>().as_ptr();
+    Some(unsafe { Vec::from_raw_parts(ptr, 0, capacity) })
+}
+
+// helpers for getting stats
+pub fn heap_size() -> usize {
+    ALLOCATOR.segments.lock().mapped
+}
+
+pub fn heap_max() -> usize {
+    ALLOCATOR.segments.lock().max
+}
+
+pub fn heap_used() -> usize {
+    ALLOCATOR.segments.lock().used()
+}
+
+pub fn heap_free() -> usize {
+    heap_size() - heap_used()
+}
+
+/// Checks the red zones and poison of every allocation, see `guard`.
+pub fn heap_check() -> guard::CheckReport {
+    GUARDED.check()
+}
+
+// per size class cache statistics
+pub fn slab_stats() -> [slab::CacheStats; slab::SIZE_CLASSES.len()] {
+    ALLOCATOR.slabs.stats()
+}
+
+use alloc::string::String;
+use alloc::format;
+pub fn heap_stat() -> String {
+    let used_kib = heap_used() / 1024;
+    let total_kib = heap_size() / 1024;
+    format!("Heap: {} / {} KiB", used_kib, total_kib)
+}
@@ -0,0 +1,140 @@
+// what the kernel does when the heap runs out
+//
+// an allocation that fails first tries to grow the heap, which `Segments`
+// does on its own. if that fails too, the registered reclaimers are asked to
+// give memory back (the slab caches register one at boot) and the allocation
+// is tried once more. when that fails as well, the allocator returns null.
+// fallible apis like `Vec::try_reserve`, `Box::try_new` or the `try_` helpers
+// in `allocator` hand that back to their caller as an error. everything else
+// ends up in the alloc error handler, which prints a report and halts.
+
+use core::{
+    alloc::Layout,
+    cmp::Reverse,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+use crate::{allocator, memory::frame::FRAME_ALLOCATOR, println, printcolor};
+
+/// A reclaim callback, returns roughly how many bytes it freed.
+///
+/// It runs with no allocator lock held and may free memory, but should not
+/// allocate.
+pub type Reclaim = fn() -> usize;
+
+const MAX_RECLAIMERS: usize = 16;
+
+// large allocations remembered for the report
+const TRACKED: usize = 8;
+
+static RECLAIMERS: Mutex<[Option<(&'static str, Reclaim)>; MAX_RECLAIMERS]> =
+    Mutex::new([None; MAX_RECLAIMERS]);
+
+// set while the reclaimers run, so a reclaimer that allocates can't recurse
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+
+/// Registers `reclaim` to be run when the heap runs out. Returns `false` if
+/// there is no room for another reclaimer.
+pub fn register_reclaimer(name: &'static str, reclaim: Reclaim) -> bool {
+    let mut reclaimers = RECLAIMERS.lock();
+    match reclaimers.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some((name, reclaim));
+            true
+        }
+        None => false,
+    }
+}
+
+// runs every reclaimer and returns the bytes they freed
+pub(super) fn reclaim() -> usize {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+
+    // copy the list so no lock is held while the reclaimers free memory
+    let reclaimers = *RECLAIMERS.lock();
+    let freed = reclaimers.iter().flatten().map(|(_, reclaim)| reclaim()).sum();
+
+    RECLAIMING.store(false, Ordering::Release);
+    freed
+}
+
+// the largest allocations currently live on the linked list heap
+//
+// only allocations bigger than the smallest one remembered get in, and a
+// freed entry stays empty until a big enough allocation comes along, so this
+// is a good guess rather than an exact list.
+pub(super) struct Largest {
+    entries: [(usize, usize); TRACKED], // address and size, size 0 is empty
+}
+
+impl Largest {
+    pub(super) const fn new() -> Self {
+        Largest { entries: [(0, 0); TRACKED] }
+    }
+
+    pub(super) fn track(&mut self, addr: usize, size: usize) {
+        let smallest = self.entries
+            .iter_mut()
+            .min_by_key(|(_, size)| *size)
+            .unwrap();
+        if size > smallest.1 {
+            *smallest = (addr, size);
+        }
+    }
+
+    pub(super) fn untrack(&mut self, addr: usize) {
+        if let Some(entry) = self.entries.iter_mut().find(|(a, size)| *a == addr && *size != 0) {
+            *entry = (0, 0);
+        }
+    }
+
+    // entries from largest to smallest
+    fn sorted(&self) -> [(usize, usize); TRACKED] {
+        let mut entries = self.entries;
+        entries.sort_unstable_by_key(|&(_, size)| Reverse(size));
+        entries
+    }
+}
+
+// the alloc error handler: an allocation failed and its caller can't cope,
+// prints what the heap looked like and halts
+//
+// nothing here may allocate.
+#[alloc_error_handler]
+fn out_of_memory(layout: Layout) -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    printcolor!(
+        Color::LightRed, Color::Black,
+        "out of memory: failed to allocate {} bytes (align {})\n",
+        layout.size(), layout.align()
+    );
+
+    match allocator::ALLOCATOR.segments.try_lock() {
+        Some(segments) => {
+            println!(
+                "heap: {} KiB used, {} KiB mapped, limit {} KiB",
+                segments.used() / 1024, segments.mapped / 1024, segments.max / 1024
+            );
+            println!("largest allocations:");
+            for (addr, size) in segments.largest.sorted().iter().filter(|(_, size)| *size != 0) {
+                println!("    {:#x}: {} bytes", addr, size);
+            }
+        }
+        None => println!("heap: locked"),
+    }
+
+    if let Some(frames) = FRAME_ALLOCATOR.try_lock() {
+        println!("frames: {} free of {}", frames.free_frames(), frames.total_frames());
+    }
+
+    println!("slab caches (size, in use, free, slabs):");
+    for cache in allocator::slab_stats() {
+        println!("    {:>4} {:>7} {:>7} {:>6}", cache.size, cache.in_use, cache.free, cache.slabs);
+    }
+
+    println!("kernel halted");
+    crate::hlt_loop();
+}
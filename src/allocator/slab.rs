@@ -0,0 +1,230 @@
+// size class caches in front of the linked list heap
+//
+// small allocations (task wakers, `Task` boxes, keyboard strings, btree
+// nodes) are rounded up to one of a few power of two sizes and served from a
+// per-size free list, so they never search the linked list and never wait on
+// another size's lock. when a cache runs dry it takes a whole slab from the
+// linked list heap and cuts it into blocks. blocks go back on their cache's
+// free list when freed, slabs are kept for reuse until the heap runs out and
+// `reclaim` hands the completely free ones back.
+
+use core::{alloc::Layout, ptr};
+use spin::Mutex;
+
+/// Block sizes of the caches. Anything bigger goes to the linked list heap.
+pub const SIZE_CLASSES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Memory taken from the linked list heap at a time.
+pub const SLAB_SIZE: usize = 4096;
+
+/// Statistics of one size class cache.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    /// Block size in bytes.
+    pub size: usize,
+    /// Blocks currently handed out.
+    pub in_use: usize,
+    /// Blocks sitting on the free list.
+    pub free: usize,
+    /// Slabs taken from the linked list heap.
+    pub slabs: usize,
+    pub allocs: u64,
+    pub frees: u64,
+}
+
+// a free block, the link lives in the block itself
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+struct Cache {
+    free_list: *mut FreeBlock,
+    stats: CacheStats,
+}
+
+// the free list is only ever touched with the lock held
+unsafe impl Send for Cache {}
+
+impl Cache {
+    const fn new(size: usize) -> Self {
+        Cache {
+            free_list: ptr::null_mut(),
+            stats: CacheStats { size, in_use: 0, free: 0, slabs: 0, allocs: 0, frees: 0 },
+        }
+    }
+
+    fn push(&mut self, block: *mut u8) {
+        let block = block as *mut FreeBlock;
+        unsafe { block.write(FreeBlock { next: self.free_list }) };
+        self.free_list = block;
+        self.stats.free += 1;
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.free_list.is_null() {
+            return None;
+        }
+        let block = self.free_list;
+        self.free_list = unsafe { (*block).next };
+        self.stats.free -= 1;
+        Some(block as *mut u8)
+    }
+
+    // cut a fresh slab into blocks
+    fn refill(&mut self, slab: *mut u8) {
+        let size = self.stats.size;
+        for offset in (0..SLAB_SIZE).step_by(size).rev() {
+            self.push(unsafe { slab.add(offset) });
+        }
+        self.stats.slabs += 1;
+    }
+
+    // gives every slab whose blocks are all free to `release`
+    fn reclaim(&mut self, release: &mut impl FnMut(*mut u8)) -> usize {
+        let per_slab = SLAB_SIZE / self.stats.size;
+        let mut released = 0;
+
+        self.free_list = unsafe { sort(self.free_list) };
+
+        // blocks of one slab are now next to each other, walk them run by run
+        let mut link: *mut *mut FreeBlock = &mut self.free_list;
+        unsafe {
+            while !(*link).is_null() {
+                let slab = slab_of(*link);
+                let mut last = *link;
+                let mut count = 1;
+                while !(*last).next.is_null() && slab_of((*last).next) == slab {
+                    last = (*last).next;
+                    count += 1;
+                }
+
+                if count == per_slab {
+                    *link = (*last).next;
+                    self.stats.free -= count;
+                    self.stats.slabs -= 1;
+                    release(slab as *mut u8);
+                    released += 1;
+                } else {
+                    link = &mut (*last).next;
+                }
+            }
+        }
+        released
+    }
+}
+
+fn slab_of(block: *mut FreeBlock) -> usize {
+    block as usize & !(SLAB_SIZE - 1)
+}
+
+// sorts a free list by address
+unsafe fn sort(list: *mut FreeBlock) -> *mut FreeBlock {
+    unsafe {
+        if list.is_null() || (*list).next.is_null() {
+            return list;
+        }
+
+        // split in the middle
+        let (mut slow, mut fast) = (list, (*list).next);
+        while !fast.is_null() && !(*fast).next.is_null() {
+            slow = (*slow).next;
+            fast = (*(*fast).next).next;
+        }
+        let second = (*slow).next;
+        (*slow).next = ptr::null_mut();
+
+        merge(sort(list), sort(second))
+    }
+}
+
+unsafe fn merge(mut a: *mut FreeBlock, mut b: *mut FreeBlock) -> *mut FreeBlock {
+    let mut head = ptr::null_mut();
+    let mut tail: *mut *mut FreeBlock = &mut head;
+
+    unsafe {
+        while !a.is_null() && !b.is_null() {
+            let next = if a < b { &mut a } else { &mut b };
+            let block = *next;
+            *next = (*block).next;
+            *tail = block;
+            tail = &mut (*block).next;
+        }
+        *tail = if a.is_null() { b } else { a };
+    }
+    head
+}
+
+/// One cache per size class.
+pub struct SlabAllocator {
+    caches: [Mutex<Cache>; SIZE_CLASSES.len()],
+}
+
+impl SlabAllocator {
+    pub(super) const fn new() -> Self {
+        let mut caches = [const { Mutex::new(Cache::new(0)) }; SIZE_CLASSES.len()];
+        let mut i = 0;
+        while i < SIZE_CLASSES.len() {
+            caches[i] = Mutex::new(Cache::new(SIZE_CLASSES[i]));
+            i += 1;
+        }
+        SlabAllocator { caches }
+    }
+
+    /// Allocates from the size class `class`, taking a new slab from
+    /// `backing` when the cache is empty.
+    pub fn alloc(&self, class: usize, backing: impl FnOnce(Layout) -> *mut u8) -> *mut u8 {
+        let mut cache = self.caches[class].lock();
+
+        let block = match cache.pop() {
+            Some(block) => block,
+            None => {
+                let slab = backing(slab_layout());
+                if slab.is_null() {
+                    return ptr::null_mut();
+                }
+                cache.refill(slab);
+                cache.pop().unwrap()
+            }
+        };
+
+        cache.stats.allocs += 1;
+        cache.stats.in_use += 1;
+        block
+    }
+
+    /// Returns a block to its size class.
+    ///
+    /// # Safety
+    /// `ptr` must come from `alloc` with the same `class`.
+    pub unsafe fn dealloc(&self, class: usize, ptr: *mut u8) {
+        let mut cache = self.caches[class].lock();
+        cache.push(ptr);
+        cache.stats.frees += 1;
+        cache.stats.in_use -= 1;
+    }
+
+    /// Hands every completely free slab to `release` and returns how many
+    /// there were.
+    pub fn reclaim(&self, mut release: impl FnMut(*mut u8)) -> usize {
+        self.caches
+            .iter()
+            .map(|cache| cache.lock().reclaim(&mut release))
+            .sum()
+    }
+
+    pub fn stats(&self) -> [CacheStats; SIZE_CLASSES.len()] {
+        core::array::from_fn(|class| self.caches[class].lock().stats)
+    }
+}
+
+/// The size class for `layout`, or `None` if it is too big for the caches.
+///
+/// Blocks are aligned to their size because slabs are page aligned.
+pub fn class_for(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align());
+    SIZE_CLASSES.iter().position(|&class| class >= size)
+}
+
+pub fn slab_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
@@ -0,0 +1,301 @@
+// heap allocation tracking
+//
+// with `heap_track=on` on the command line, every live allocation is
+// recorded with its size, the task that made it and the return addresses of
+// the frames that called the allocator. the records live in a hash table
+// taken straight from the frame allocator, so recording never touches the
+// heap it is watching.
+//
+// `mark` remembers the current allocation number. everything allocated after
+// it and still live shows up in `growth`, grouped by caller, which is how a
+// leaking task gives itself away.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    slice,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use spin::Mutex;
+use crate::{
+    backtrace,
+    memory::{PHYS_OFFSET, frame::{FRAME_ALLOCATOR, FRAME_SIZE}},
+    task,
+};
+
+/// Return addresses kept per allocation.
+pub const CALLERS: usize = 6;
+
+// innermost frames that every allocation through the global allocator goes
+// through and that say nothing about who allocated: the `__rust_alloc` shim
+// and the `alloc::alloc` call in front of it
+pub(super) const GLOBAL_ALLOC_FRAMES: usize = 2;
+
+// most frames `record` can be asked to skip
+const MAX_SKIP: usize = GLOBAL_ALLOC_FRAMES;
+
+// records in the table, a power of two
+const CAPACITY: usize = 8192;
+
+// the table is never filled beyond this, so probing stays short
+const MAX_LIVE: usize = CAPACITY / 8 * 7;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TABLE: Mutex<Option<Table>> = Mutex::new(None);
+
+/// A live allocation.
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub addr: usize, // 0 for an empty record
+    pub size: usize,
+    /// Allocation number, counting up from when tracking was enabled.
+    pub seq: u64,
+    pub task: Option<u64>,
+    /// Innermost first, without the allocator's own frames. The first ones
+    /// can still be generic code like `Box::new` or a `Vec` growing.
+    pub callers: [usize; CALLERS],
+}
+
+impl Allocation {
+    pub const EMPTY: Allocation = Allocation { addr: 0, size: 0, seq: 0, task: None, callers: [0; CALLERS] };
+}
+
+/// Allocations made since the checkpoint that share a task and callers.
+#[derive(Debug, Clone, Copy)]
+pub struct Growth {
+    pub task: Option<u64>,
+    pub callers: [usize; CALLERS],
+    pub count: usize,
+    pub bytes: usize,
+}
+
+impl Growth {
+    pub const EMPTY: Growth = Growth { task: None, callers: [0; CALLERS], count: 0, bytes: 0 };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stats {
+    pub live: usize,
+    pub bytes: usize,
+    /// Allocations that were not recorded because the table was full.
+    pub dropped: usize,
+}
+
+// open addressing with linear probing, keyed by address
+struct Table {
+    records: &'static mut [Allocation],
+    live: usize,
+    bytes: usize,
+    dropped: usize,
+    next_seq: u64,
+    checkpoint: u64,
+}
+
+impl Table {
+    fn home(addr: usize) -> usize {
+        ((addr >> 3) as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) as usize % CAPACITY
+    }
+
+    fn insert(&mut self, mut record: Allocation) {
+        if self.live >= MAX_LIVE {
+            self.dropped += 1;
+            return;
+        }
+
+        record.seq = self.next_seq;
+        self.next_seq += 1;
+
+        let mut slot = Self::home(record.addr);
+        while self.records[slot].addr != 0 {
+            slot = (slot + 1) % CAPACITY;
+        }
+        self.records[slot] = record;
+        self.live += 1;
+        self.bytes += record.size;
+    }
+
+    fn remove(&mut self, addr: usize) {
+        let mut slot = Self::home(addr);
+        loop {
+            match self.records[slot].addr {
+                0 => return, // allocated before tracking started, or dropped
+                a if a == addr => break,
+                _ => slot = (slot + 1) % CAPACITY,
+            }
+        }
+        self.live -= 1;
+        self.bytes -= self.records[slot].size;
+
+        // shift later records of the probe run back into the hole
+        let mut hole = slot;
+        let mut next = slot;
+        loop {
+            next = (next + 1) % CAPACITY;
+            let addr = self.records[next].addr;
+            if addr == 0 {
+                break;
+            }
+            let home = Self::home(addr);
+            let distance_home = (next + CAPACITY - home) % CAPACITY;
+            let distance_hole = (next + CAPACITY - hole) % CAPACITY;
+            if distance_home >= distance_hole {
+                self.records[hole] = self.records[next];
+                hole = next;
+            }
+        }
+        self.records[hole] = Allocation::EMPTY;
+    }
+
+    fn live_records(&self) -> impl Iterator<Item = &Allocation> {
+        self.records.iter().filter(|record| record.addr != 0)
+    }
+}
+
+/// Wraps the kernel heap and records every allocation while tracking is
+/// enabled.
+pub struct Tracking<A: 'static> {
+    inner: &'static A,
+}
+
+impl<A> Tracking<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        Tracking { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracking<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        record(ptr, layout.size(), GLOBAL_ALLOC_FRAMES);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        forget(ptr);
+        unsafe { self.inner.dealloc(ptr, layout) };
+    }
+}
+
+// records a new allocation, leaving out the `skip` innermost return
+// addresses of the function it is inlined into
+#[inline(always)]
+pub(super) fn record(ptr: *mut u8, size: usize, skip: usize) {
+    if !ENABLED.load(Ordering::Relaxed) || ptr.is_null() {
+        return;
+    }
+
+    let skip = skip.min(MAX_SKIP);
+    let mut trace = [0; MAX_SKIP + CALLERS];
+    backtrace::return_addresses(&mut trace[..skip + CALLERS]);
+    let mut callers = [0; CALLERS];
+    callers.copy_from_slice(&trace[skip..skip + CALLERS]);
+
+    if let Some(table) = TABLE.lock().as_mut() {
+        table.insert(Allocation { addr: ptr as usize, size, seq: 0, task: task::current(), callers });
+    }
+}
+
+// drops the record of a freed allocation
+pub(super) fn forget(ptr: *mut u8) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(table) = TABLE.lock().as_mut() {
+        table.remove(ptr as usize);
+    }
+}
+
+/// Enables tracking if `heap_track=on` is on the command line. Call once
+/// after the frame allocator is up.
+pub fn init() {
+    if crate::cmdline::flag("heap_track") != Some(true) {
+        return;
+    }
+
+    let bytes = CAPACITY * size_of::<Allocation>();
+    let frames = bytes.div_ceil(FRAME_SIZE as usize);
+    let Some(start) = FRAME_ALLOCATOR.lock().allocate_contiguous(frames, 1) else {
+        crate::println!("heap tracking: no memory for the table");
+        return;
+    };
+
+    let records = unsafe {
+        let ptr = (PHYS_OFFSET + start.start_address().as_u64()) as *mut Allocation;
+        for i in 0..CAPACITY {
+            ptr.add(i).write(Allocation::EMPTY);
+        }
+        slice::from_raw_parts_mut(ptr, CAPACITY)
+    };
+
+    *TABLE.lock() = Some(Table { records, live: 0, bytes: 0, dropped: 0, next_seq: 0, checkpoint: 0 });
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn stats() -> Option<Stats> {
+    let table = TABLE.lock();
+    let table = table.as_ref()?;
+    Some(Stats { live: table.live, bytes: table.bytes, dropped: table.dropped })
+}
+
+/// Fills `out` with the largest live allocations, largest first, and returns
+/// how many there are.
+pub fn largest(out: &mut [Allocation]) -> usize {
+    let table = TABLE.lock();
+    let Some(table) = table.as_ref() else {
+        return 0;
+    };
+
+    // keep `out` sorted and push smaller ones off the end
+    let mut count = 0;
+    for record in table.live_records() {
+        let pos = out[..count].iter().position(|a| a.size < record.size).unwrap_or(count);
+        if pos == out.len() {
+            continue;
+        }
+        count = (count + 1).min(out.len());
+        out[pos..count].rotate_right(1);
+        out[pos] = *record;
+    }
+    count
+}
+
+/// Sets the checkpoint for `growth` to now.
+pub fn mark() {
+    if let Some(table) = TABLE.lock().as_mut() {
+        table.checkpoint = table.next_seq;
+    }
+}
+
+/// Groups the allocations made since the last `mark` that are still live
+/// by task and callers, fills `out` with the groups, most bytes first, and
+/// returns how many there are. Groups that don't fit in `out` are left out.
+pub fn growth(out: &mut [Growth]) -> usize {
+    let table = TABLE.lock();
+    let Some(table) = table.as_ref() else {
+        return 0;
+    };
+
+    let mut count = 0;
+    for record in table.live_records().filter(|r| r.seq >= table.checkpoint) {
+        let group = out[..count]
+            .iter()
+            .position(|g| g.task == record.task && g.callers == record.callers);
+        let group = match group {
+            Some(group) => group,
+            None if count < out.len() => {
+                out[count] = Growth { task: record.task, callers: record.callers, count: 0, bytes: 0 };
+                count += 1;
+                count - 1
+            }
+            None => continue,
+        };
+        out[group].count += 1;
+        out[group].bytes += record.size;
+    }
+
+    out[..count].sort_unstable_by_key(|g| core::cmp::Reverse(g.bytes));
+    count
+}
@@ -0,0 +1,245 @@
+// local apic and io apic
+//
+// the 8259 pics are only there for compatibility. when the madt describes
+// the apics, `init` masks the pics and routes the legacy irqs we use through
+// an io apic instead, at the same vectors (see `InterruptIndex`), so the
+// handlers don't care which controller delivered an interrupt. they
+// acknowledge it with `interrupts::end_of_interrupt`.
+//
+// `apic=off` on the command line keeps the pics.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use conquer_once::spin::OnceCell;
+use raw_cpuid::CpuId;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr, registers::model_specific::Msr};
+use crate::{
+    acpi::madt::{self, Entry, Madt},
+    cmdline,
+    interrupts::{InterruptIndex, PICS},
+    memory::mmio,
+};
+
+// apic base msr and its global enable bit
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local apic registers, as byte offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_VERSION: usize = 0x30;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_SIZE: usize = 0x400;
+
+// spurious vector register: apic software enable
+const SVR_ENABLE: u32 = 1 << 8;
+
+// io apic registers: select a register, then read or write it through the window
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_SIZE: usize = 0x20;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+// redirection entry bits
+const REDIRECT_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECT_LEVEL: u64 = 1 << 15;
+const REDIRECT_MASKED: u64 = 1 << 16;
+
+// most io apics we keep track of
+const MAX_IOAPICS: usize = 8;
+
+// legacy isa irqs we route
+const IRQ_TIMER: u8 = 0;
+const IRQ_KEYBOARD: u8 = 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LAPIC: OnceCell<LocalApic> = OnceCell::uninit();
+static IOAPICS: Mutex<[Option<IoApic>; MAX_IOAPICS]> = Mutex::new([None; MAX_IOAPICS]);
+
+/// The local apic of the cpu we run on.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub phys: PhysAddr,
+    regs: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.regs + reg as u64).as_ptr::<u32>()) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.regs + reg as u64).as_mut_ptr::<u32>(), value) }
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    pub fn version(&self) -> u8 {
+        self.read(LAPIC_VERSION) as u8
+    }
+}
+
+/// An io apic, handling the gsis from `gsi_base` on.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub phys: PhysAddr,
+    pub gsi_base: u32,
+    /// Number of inputs.
+    pub inputs: u32,
+    regs: VirtAddr,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.regs + IOREGSEL as u64).as_mut_ptr::<u32>(), reg);
+            ptr::read_volatile((self.regs + IOWIN as u64).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.regs + IOREGSEL as u64).as_mut_ptr::<u32>(), reg);
+            ptr::write_volatile((self.regs + IOWIN as u64).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.inputs
+    }
+
+    fn set_redirection(&self, input: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION + input * 2;
+        // masked while the halves disagree
+        self.write(reg, REDIRECT_MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+/// Whether interrupts go through the apics rather than the pics.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The local apic, once `init` has enabled it.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LAPIC.get()
+}
+
+/// Calls `f` for every io apic.
+pub fn io_apics(mut f: impl FnMut(&IoApic)) {
+    for ioapic in IOAPICS.lock().iter().flatten() {
+        f(ioapic);
+    }
+}
+
+/// Switches from the pics to the apics if the madt describes them. Returns
+/// whether it did.
+///
+/// Call after `acpi::init`, with the pics initialized.
+pub fn init() -> bool {
+    if cmdline::flag("apic") == Some(false) {
+        return false;
+    }
+    let has_apic = CpuId::new().get_feature_info().is_some_and(|f| f.has_apic());
+    let Some(madt) = madt::get().filter(|_| has_apic) else {
+        return false;
+    };
+
+    // the local apic first, nothing is left behind if it can't be mapped
+    let phys = PhysAddr::new(madt.local_apic_address);
+    let Ok(regs) = mmio::map_mmio(phys, LAPIC_SIZE) else {
+        return false;
+    };
+    if add_io_apics(&madt) == 0 {
+        let _ = unsafe { mmio::unmap_mmio(regs) };
+        return false;
+    }
+    let lapic = LocalApic { phys, regs: VirtAddr::from_ptr(regs.as_ptr()) };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe {
+            PICS.lock().disable();
+
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read();
+            base.write(value | APIC_BASE_ENABLE);
+        }
+        lapic.write(LAPIC_TPR, 0);
+        lapic.write(LAPIC_SVR, SVR_ENABLE | InterruptIndex::Spurious as u32);
+        let _ = LAPIC.try_init_once(|| lapic);
+
+        route(&madt, lapic.id(), IRQ_TIMER, InterruptIndex::Timer);
+        route(&madt, lapic.id(), IRQ_KEYBOARD, InterruptIndex::Keyboard);
+        ENABLED.store(true, Ordering::Relaxed);
+    });
+    true
+}
+
+/// Acknowledges the interrupt being handled.
+pub fn end_of_interrupt() {
+    if let Some(lapic) = LAPIC.get() {
+        lapic.write(LAPIC_EOI, 0);
+    }
+}
+
+// maps every io apic in the madt with all its inputs masked, returns how many
+fn add_io_apics(madt: &Madt) -> usize {
+    let mut ioapics = IOAPICS.lock();
+    let mut count = 0;
+    for entry in madt.entries() {
+        let Entry::IoApic { id, address, gsi_base } = entry else {
+            continue;
+        };
+        if count == MAX_IOAPICS {
+            break;
+        }
+        let phys = PhysAddr::new(address as u64);
+        let Ok(regs) = mmio::map_mmio(phys, IOAPIC_SIZE) else {
+            continue;
+        };
+
+        let mut ioapic = IoApic { id, phys, gsi_base, inputs: 0, regs: VirtAddr::from_ptr(regs.as_ptr()) };
+        ioapic.inputs = ((ioapic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+        for input in 0..ioapic.inputs {
+            ioapic.set_redirection(input, REDIRECT_MASKED);
+        }
+
+        ioapics[count] = Some(ioapic);
+        count += 1;
+    }
+    count
+}
+
+// sends isa irq `irq` to `vector` on the cpu with local apic `apic_id`
+fn route(madt: &Madt, apic_id: u8, irq: u8, vector: InterruptIndex) {
+    let (gsi, mut entry) = match madt.interrupt_override(irq) {
+        Some(o) => {
+            let mut entry = 0;
+            if o.active_low() {
+                entry |= REDIRECT_ACTIVE_LOW;
+            }
+            if o.level_triggered() {
+                entry |= REDIRECT_LEVEL;
+            }
+            (o.gsi, entry)
+        }
+        // isa default: same number, edge triggered, active high
+        None => (irq as u32, 0),
+    };
+    entry |= vector as u64 | (apic_id as u64) << 56;
+
+    let ioapics = IOAPICS.lock();
+    if let Some(ioapic) = ioapics.iter().flatten().find(|ioapic| ioapic.handles(gsi)) {
+        ioapic.set_redirection(gsi - ioapic.gsi_base, entry);
+    }
+}
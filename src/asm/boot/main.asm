@@ -1,51 +1,20 @@
 global start
-global boot_info
-%define PHYS_OFFSET 0xFFFF800000000000
+global multiboot_info
 extern long_mode_start
 
-section .data
-align 16
-
-; BootInfo struct
-boot_info:
-    dq 0                   ; api_version = 0
-    dq memory_regions      ; ptr to MemoryRegions array
-    dq 2                   ; len = 2
-    dq 0                   ; framebuffer = None
-    dq PHYS_OFFSET         ; physical_memory_offset
-    dq 0                   ; recursive_index = None
-    dq 0                   ; rsdp_addr = None
-    dq 0                   ; tls_template = None
-    dq 0                   ; ramdisk_addr = None
-    dq 0                   ; ramdisk_len
-    dq 0                   ; kernel_addr
-    dq 0                   ; kernel_len
-    dq 0                   ; kernel_image_offset
-    dq 0                   ; kernel_stack_bottom
-    dq 0                   ; kernel_stack_len
-    dq 0                   ; _test_sentinel
-
-; FFI-safe MemoryRegions struct
-memory_regions:
-    dq memory_region_0      ; pointer to first MemoryRegion
-    dq memory_region_1      ; pointer to second MemoryRegion
-    dq 2                    ; number of regions
-
-; MemoryRegion itself
-memory_region_0:
-    dq 0x00000000
-    dq 0x100000   ; kernel reserved
-    dq 0          ; reserved
-
-memory_region_1:
-    dq 0x100000   ; start of usable RAM
-    dq 0x40000000 ; 1 GiB
-    dq 1          ; usable
-    
-section .text
+; everything in here runs identity mapped before the jump to the higher half,
+; so it lives in the .boot sections that are linked at their physical address
+section .boot.data progbits alloc noexec write align=8
+
+; physical address of the multiboot2 information structure, saved by start
+multiboot_info:
+    dq 0
+
+section .boot.text progbits alloc exec nowrite align=16
 [BITS 32]
 start:
     mov esp, stack_top
+    mov [multiboot_info], ebx ; save before cpuid clobbers ebx
 
     call check_multiboot
     call check_cpuid
@@ -117,28 +86,35 @@ setup_page_tables:
     or eax, 0b11
     mov [page_table_l4 + 256*8], eax
 
+    ; L4[511] -> L3 (kernel image)
+    ; KERNEL_BASE = 0xFFFFFFFF80000000
+    mov eax, page_table_l3_kernel
+    or eax, 0b11
+    mov [page_table_l4 + 511*8], eax
+
     ; L3[0] -> L2
     mov eax, page_table_l2
     or eax, 0b11
     mov [page_table_l3 + 0*8], eax
 
-    ; L2[0] -> L1
-    mov eax, page_table_l1
+    ; L3_kernel[510] -> L2, the same first 1 GiB
+    mov eax, page_table_l2
     or eax, 0b11
-    mov [page_table_l2 + 0*8], eax
+    mov [page_table_l3_kernel + 510*8], eax
 
-    ; map first 2 MiB using 4 KiB pages
+    ; map the first 1 GiB using 2 MiB pages, so the multiboot
+    ; information is reachable wherever grub put it
     xor ecx, ecx                  ; start at page 0
 
-.map_l1:
-    mov eax, ecx
-    shl eax, 12                   ; phys = ecx * 4096
-    or eax, 0b11                  ; present + writable
-    mov [page_table_l1 + ecx*8], eax
+.map_l2:
+    mov eax, 0x200000
+    mul ecx                       ; phys = ecx * 2MiB
+    or eax, 0b10000011            ; present + writable + huge
+    mov [page_table_l2 + ecx*8], eax
 
     inc ecx
-    cmp ecx, 512                  ; 512 * 4KiB = 2MiB
-    jne .map_l1
+    cmp ecx, 512                  ; 512 * 2MiB = 1GiB
+    jne .map_l2
 
     ret
 
@@ -156,11 +132,13 @@ enable_paging:
     mov ecx, 0xC0000080 ; magic value again :O
     rdmsr
     or eax, 1 << 8 ; bit 8 is the long mode flag
+    or eax, 1 << 11 ; bit 11 is the no-execute enable flag
     wrmsr ; write to module specific register
 
     ; enable paging
     mov eax, cr0
     or eax, 1 << 31 ; bit 31, enable paging flag
+    or eax, 1 << 16 ; bit 16, write protect, so the kernel faults on read-only pages too
     mov cr0, eax
 
     ret
@@ -171,21 +149,20 @@ error:
     mov word [0xB8004], 0x4F52
     hlt
 
-section .bss
-align 4096
+section .boot.bss nobits alloc noexec write align=4096
 page_table_l4:
     resb 4096
 page_table_l3:
     resb 4096
-page_table_l2:
+page_table_l3_kernel:
     resb 4096
-page_table_l1:
+page_table_l2:
     resb 4096
 stack_bottom:
     resb 4096 * 4 ; defines a 4 kilobyte stack
 stack_top:
 
-section .rodata 
+section .boot.rodata progbits alloc noexec nowrite align=8
 gdt64:
     dq 0 ; zero entry
 .code_segment: equ $ - gdt64
@@ -1,15 +1,12 @@
 global long_mode_start
 extern _start
-extern boot_info
+extern multiboot_info
 
 
-section .text
+section .boot.text progbits alloc exec nowrite align=16
 [BITS 64]
 long_mode_start:
     ; load null into all data segment registers
-
-    mov rsp, stack_top_64
-
     mov ax, 0
     mov ss, ax
     mov ds, ax
@@ -17,7 +14,16 @@ long_mode_start:
     mov fs, ax
     mov gs, ax
 
-    mov rdi, boot_info   ; use boot_info as first argument to _start
+    ; still running identity mapped, jump to the higher half
+    mov rax, higher_half_start
+    jmp rax
+
+section .text
+higher_half_start:
+    mov rsp, stack_top_64
+
+    mov rdi, [multiboot_info] ; multiboot info address is the first argument to _start
+    xor rbp, rbp              ; end of the frame pointer chain
     call _start
 
 .halt:
@@ -0,0 +1,49 @@
+// stack walking through frame pointers
+//
+// the target spec keeps frame pointers in every function, so each frame
+// starts with the caller's rbp followed by the return address:
+//
+//     [rbp + 8]  return address
+//     [rbp]      caller's rbp
+//
+// the boot code clears rbp before calling `_start`, which ends the chain.
+// addresses can be turned into functions with `addr2line -e kernel.bin`.
+
+use core::arch::asm;
+
+// frames live on kernel stacks, which are all in the higher half
+const KERNEL_SPACE: usize = 0xFFFF_8000_0000_0000;
+
+/// Fills `out` with return addresses, starting with the one of the function
+/// that calls `return_addresses`, and returns how many were found.
+#[inline(always)]
+pub fn return_addresses(out: &mut [usize]) -> usize {
+    let rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    unsafe { walk(rbp, out) }
+}
+
+/// Fills `out` with the return addresses of the chain starting at `rbp`.
+///
+/// # Safety
+/// `rbp` must be 0 or point to a frame on a mapped kernel stack.
+pub unsafe fn walk(mut rbp: usize, out: &mut [usize]) -> usize {
+    let mut count = 0;
+
+    while count < out.len() && rbp >= KERNEL_SPACE && rbp.is_multiple_of(8) {
+        let frame = rbp as *const usize;
+        let (next, ret) = unsafe { (*frame, *frame.add(1)) };
+        if ret == 0 {
+            break;
+        }
+        out[count] = ret;
+        count += 1;
+
+        // frames only ever go up the stack, anything else is garbage
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    count
+}
@@ -0,0 +1,110 @@
+// kernel command line
+//
+// options come from the multiboot2 line in grub.cfg, e.g.
+//
+//     multiboot2 /boot/kernel.bin loglevel=debug console=serial heap=4M shell=off
+//
+// each option is either `key=value` or a bare `key`, separated by whitespace.
+// if a key is given more than once the last one wins.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use conquer_once::spin::OnceCell;
+use crate::multiboot;
+
+static CMDLINE: OnceCell<&'static str> = OnceCell::uninit();
+
+// `console=`, parsed once by `init` since every print looks at it
+static CONSOLE: AtomicU8 = AtomicU8::new(Console::Vga as u8);
+
+/// Reads the command line from the multiboot2 info. Call once after
+/// `multiboot::init`.
+pub fn init() {
+    let cmdline = multiboot::info()
+        .and_then(|info| info.command_line())
+        .unwrap_or("");
+    let _ = CMDLINE.try_init_once(|| cmdline);
+
+    let console = match get("console") {
+        Some("serial") => Console::Serial,
+        Some("both") => Console::Both,
+        _ => Console::Vga,
+    };
+    CONSOLE.store(console as u8, Ordering::Relaxed);
+}
+
+/// The whole command line as passed by the bootloader.
+pub fn raw() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+/// Value of `key=value`, or an empty string for a bare `key`.
+pub fn get(key: &str) -> Option<&'static str> {
+    raw()
+        .split_whitespace()
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+        .filter(|(k, _)| *k == key)
+        .map(|(_, value)| value)
+        .next_back()
+}
+
+/// Parses `on`/`off` style values. A bare `key` counts as on.
+pub fn flag(key: &str) -> Option<bool> {
+    match get(key)? {
+        "" | "1" | "on" | "yes" | "true" => Some(true),
+        "0" | "off" | "no" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+pub fn number(key: &str) -> Option<u64> {
+    get(key)?.parse().ok()
+}
+
+/// Parses sizes like `4096`, `512K`, `4M` or `1G`.
+pub fn size(key: &str) -> Option<usize> {
+    let value = get(key)?;
+    let (digits, shift) = match value.as_bytes().last()? {
+        b'k' | b'K' => (&value[..value.len() - 1], 10),
+        b'm' | b'M' => (&value[..value.len() - 1], 20),
+        b'g' | b'G' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// `loglevel=error|warn|info|debug|trace`, defaults to info.
+pub fn loglevel() -> LogLevel {
+    match get("loglevel") {
+        Some("error") => LogLevel::Error,
+        Some("warn") => LogLevel::Warn,
+        Some("debug") => LogLevel::Debug,
+        Some("trace") => LogLevel::Trace,
+        _ => LogLevel::Info,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Console {
+    Vga,
+    Serial,
+    Both,
+}
+
+/// `console=vga|serial|both`, defaults to vga.
+pub fn console() -> Console {
+    match CONSOLE.load(Ordering::Relaxed) {
+        console if console == Console::Serial as u8 => Console::Serial,
+        console if console == Console::Both as u8 => Console::Both,
+        _ => Console::Vga,
+    }
+}
@@ -0,0 +1,231 @@
+// cpu exceptions
+//
+// every exception vector the idt lets us set gets a handler. each prints a
+// kernel oops to both the screen and the serial port, naming the exception,
+// the task that was running, the error code, the interrupted context and the
+// control registers, then halts. breakpoints, debug traps and nmis are
+// reported and execution goes on. page faults first go to the fault
+// resolvers, see `memory::fault`, and only oops if none of them can help.
+//
+// vectors 15, 22-27 and 31 are reserved: the cpu never raises them and the
+// idt type has no way to set them.
+
+use x86_64::{
+    registers::control::{Cr0, Cr2, Cr3, Cr4},
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+};
+use crate::{
+    eprintln,
+    gdt,
+    memory::{self, fault::PageFault},
+    task,
+};
+
+/// Names of the 32 architectural exceptions, by vector.
+pub const NAMES: [&str; 32] = [
+    "divide error",
+    "debug",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid tss",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved",
+    "x87 floating point",
+    "alignment check",
+    "machine check",
+    "simd floating point",
+    "virtualization",
+    "control protection",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection",
+    "vmm communication",
+    "security",
+    "reserved",
+];
+
+// exceptions without an error code that halt
+macro_rules! fatal {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+            oops($vector, &stack_frame, None, None, None);
+        }
+    };
+}
+
+// exceptions with an error code that halt
+macro_rules! fatal_with_code {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, error_code: u64) {
+            oops($vector, &stack_frame, Some(error_code), None, None);
+        }
+    };
+}
+
+// exceptions that are reported and then returned from
+macro_rules! report_only {
+    ($name:ident, $vector:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+            report($vector, &stack_frame, None);
+        }
+    };
+}
+
+fatal!(divide_error_handler, 0);
+report_only!(debug_handler, 1);
+report_only!(nmi_handler, 2);
+report_only!(breakpoint_handler, 3);
+fatal!(overflow_handler, 4);
+fatal!(bound_range_handler, 5);
+fatal!(invalid_opcode_handler, 6);
+fatal!(device_not_available_handler, 7);
+fatal!(coprocessor_segment_overrun_handler, 9);
+fatal_with_code!(invalid_tss_handler, 10);
+fatal_with_code!(segment_not_present_handler, 11);
+fatal_with_code!(stack_segment_handler, 12);
+fatal_with_code!(general_protection_handler, 13);
+fatal!(x87_floating_point_handler, 16);
+fatal_with_code!(alignment_check_handler, 17);
+fatal!(simd_floating_point_handler, 19);
+fatal!(virtualization_handler, 20);
+fatal_with_code!(control_protection_handler, 21);
+fatal!(hv_injection_handler, 28);
+fatal_with_code!(vmm_communication_handler, 29);
+fatal_with_code!(security_handler, 30);
+
+/// Installs the exception handlers.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    idt[9].set_handler_fn(coprocessor_segment_overrun_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception.set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception.set_handler_fn(hv_injection_handler);
+    idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_handler);
+
+    // page faults stay on the current stack, so resolvers may fault in turn.
+    // double faults get their own, a stack overflow ends up there
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    unsafe {
+        idt.double_fault.set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+}
+
+// prints everything known about an exception
+fn report(vector: u8, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    eprintln!("exception {}: {}", vector, NAMES[vector as usize]);
+    match error_code {
+        // these error codes name a segment selector
+        Some(code) if (10..=13).contains(&vector) && code != 0 => eprintln!(
+            "error code: {:#x} (selector index {}, {}{})",
+            code,
+            code >> 3,
+            ["gdt", "idt", "ldt", "idt"][(code as usize >> 1) & 3],
+            if code & 1 != 0 { ", external" } else { "" },
+        ),
+        Some(code) => eprintln!("error code: {:#x}", code),
+        None => {}
+    }
+    eprintln!("{:#?}", stack_frame);
+    eprintln!(
+        "cr0: {:#x}  cr3: {:#x}  cr4: {:#x}",
+        Cr0::read_raw(), Cr3::read().0.start_address(), Cr4::read_raw()
+    );
+}
+
+// prints a kernel oops and halts. `reason` says why the exception is fatal
+// when that is more than the exception itself, `fault` is the decoded page
+// fault if it was one.
+fn oops(
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+    reason: Option<&str>,
+    fault: Option<&PageFault>,
+) -> ! {
+    x86_64::instructions::interrupts::disable();
+    // nothing we interrupted runs again, the output locks are ours
+    crate::vga::force_output();
+
+    eprintln!("------------[ kernel oops ]------------");
+    if let Some(reason) = reason {
+        eprintln!("{}", reason);
+    }
+    match task::current() {
+        Some(task) => eprintln!("task: {}", task),
+        None => eprintln!("task: none (cpu 0)"),
+    }
+    if let Some(fault) = fault {
+        eprintln!("fault: {}", fault);
+    }
+
+    // double faults and page faults below a stack are most likely overflows
+    if matches!(vector, 8 | 14) {
+        let addr = fault.map_or_else(Cr2::read, |fault| fault.addr);
+        if let Some(stack) = memory::stack::overflowed(addr) {
+            eprintln!("stack: {} overflowed at {:#x}", stack.name, addr);
+        }
+    }
+
+    report(vector, stack_frame, error_code);
+    eprintln!("------------[ end kernel oops ]------------");
+    halt();
+}
+
+fn halt() -> ! {
+    x86_64::instructions::interrupts::disable();
+    eprintln!("kernel halted");
+    crate::hlt_loop();
+}
+
+// double fault handler, never returns
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame, error_code: u64
+) -> ! {
+    oops(8, &stack_frame, Some(error_code), None, None);
+}
+
+// machine check handler, never returns
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    oops(18, &stack_frame, None, None, None);
+}
+
+// page fault handler
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let fault = PageFault::new(Cr2::read(), stack_frame.instruction_pointer, error_code);
+    if let Err(reason) = memory::fault::resolve(&fault) {
+        oops(14, &stack_frame, Some(error_code.bits()), Some(reason), Some(&fault));
+    }
+}
@@ -8,26 +8,25 @@ use x86_64::structures::{
     }
 };
 use lazy_static::lazy_static;
+use crate::memory::stack;
 
-// index in ist for double fault
+// index in ist for double fault. a kernel stack overflow faults on the guard
+// page while pushing the page fault frame, which turns into a double fault,
+// so overflows are reported on this stack
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    // tss with interrupt stacks
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        // setup stack for double fault
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5; // 5 pages
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // stack grows down, so use end
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
+// size of each interrupt stack
+const IST_STACK_SIZE: usize = 4096 * 5; // 5 pages
+
+// tss with interrupt stacks
+//
+// starts out with stacks in .bss so exceptions work during early boot,
+// `init_stacks` swaps them for guarded ones once memory is set up. the cpu
+// reads the ist on every interrupt, so changing it needs no reload.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+fn set_ist(index: u16, stack_end: VirtAddr) {
+    unsafe { TSS.interrupt_stack_table[index as usize] = stack_end };
 }
 
 lazy_static! {
@@ -35,7 +34,7 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment()); // kernel code
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS)); // tss segment
+        let tss_selector = gdt.add_entry(unsafe { Descriptor::tss_segment_unchecked(&raw const TSS) }); // tss segment
         (gdt, Selectors { code_selector, tss_selector })
     };
 }
@@ -50,9 +49,25 @@ pub fn init() {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::{CS, Segment};
 
+    // early interrupt stack
+    static mut DOUBLE_FAULT_STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+
+    // stack grows down, so use end
+    set_ist(DOUBLE_FAULT_IST_INDEX, VirtAddr::from_ptr(&raw const DOUBLE_FAULT_STACK) + IST_STACK_SIZE);
+
     GDT.0.load(); // load gdt
     unsafe {
         CS::set_reg(GDT.1.code_selector); // switch code segment
         load_tss(GDT.1.tss_selector); // load tss
     }
-}
\ No newline at end of file
+}
+
+// moves the interrupt stack to a guarded kernel stack, call once memory is up
+pub fn init_stacks() {
+    let double_fault = stack::allocate(IST_STACK_SIZE as u64, "double fault stack")
+        .expect("gdt: no memory for the double fault stack");
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        set_ist(DOUBLE_FAULT_IST_INDEX, double_fault.top);
+    });
+}
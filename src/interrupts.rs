@@ -1,9 +1,8 @@
 use x86_64::structures::idt::{
     InterruptDescriptorTable,
     InterruptStackFrame,
-    PageFaultErrorCode,
 };
-use crate::{println, gdt};
+use crate::{apic, exceptions};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use core::sync::atomic::AtomicU64;
@@ -20,12 +19,19 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8; // offset for slave pic
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
-// interrupt indexes for easy reference
+// pic command ports, for reading the in-service register
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_READ_ISR: u8 = 0x0B; // ocw3
+const PIC_EOI: u8 = 0x20;
+
+// interrupt indexes for easy reference, the same with the pics or the apics
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,  // timer interrupt
     Keyboard,              // keyboard interrupt
+    Spurious = 0xFF,       // local apic spurious interrupt
 }
 
 impl InterruptIndex {
@@ -43,14 +49,8 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
 
-        // called when int3 is hit (breakpoint)
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        
-        // called on a double fault (very bad)
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+        // cpu exceptions
+        exceptions::install(&mut idt);
 
         // timer interrupt
         idt[InterruptIndex::Timer.as_usize()]
@@ -60,8 +60,14 @@ lazy_static! {
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
 
-        // page fault exception
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        // spurious interrupts, from the local apic or from the pics. irq 7
+        // and 15 may also be real ones
+        idt[InterruptIndex::Spurious.as_usize()]
+            .set_handler_fn(spurious_interrupt_handler);
+        idt[usize::from(PIC_1_OFFSET + 7)]
+            .set_handler_fn(pic_1_irq7_handler);
+        idt[usize::from(PIC_2_OFFSET + 7)]
+            .set_handler_fn(pic_2_irq7_handler);
 
         idt
     };
@@ -72,18 +78,13 @@ pub fn init_idt() {
     IDT.load();
 }
 
-// breakpoint exception handler
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: InterruptStackFrame
-) {
-    println!("exception: breakpoint\n{:#?}", stack_frame);
-}
-
-// double fault handler, never returns
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64
-) -> ! {
-    panic!("exception: double fault\n{:#?}", stack_frame);
+// tell whichever controller delivered the interrupt that it is handled
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if apic::enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
 }
 
 // timer interrupt handler
@@ -93,11 +94,8 @@ extern "x86-interrupt" fn timer_interrupt_handler(
     // increment timer tick count
     crate::timer::tick();
 
-    // notify pic that interrupt is handled
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    // notify the interrupt controller that interrupt is handled
+    end_of_interrupt(InterruptIndex::Timer);
 }
 
 // keyboard interrupt handler
@@ -128,23 +126,46 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     // temporarily remove usb cause it was causing issues
     //keyboard::add_usb_scancode(scancode);
 
-    // notify pic that interrupt is handled
+    // notify the interrupt controller that interrupt is handled
+    end_of_interrupt(InterruptIndex::Keyboard);
+} // fn keyboard_interrupt_handler
+
+// local apic spurious interrupt handler, these must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame
+) {}
+
+// reads the in-service register of the pic at `command`
+fn pic_in_service(command: u16) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::<u8>::new(command);
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        port.write(PIC_READ_ISR);
+        port.read()
     }
-} // fn keyboard_interrupt_handler
+}
 
-// page fault handler
-extern "x86-interrupt" fn page_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
+// irq 7 is what the master pic raises when an interrupt goes away before it
+// is acknowledged. only a real one has its in-service bit set and gets an eoi
+extern "x86-interrupt" fn pic_1_irq7_handler(
+    _stack_frame: InterruptStackFrame
 ) {
-    println!("page fault");
-    println!("error code: {}", error_code.bits() as u64);
+    if pic_in_service(PIC_1_COMMAND) & (1 << 7) != 0 {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + 7) };
+    }
+}
 
-    // halt cpu forever after page fault
-    loop {
-        x86_64::instructions::hlt();
+// the same for irq 15 on the slave pic. the master did see an interrupt on
+// its cascade line though, so it gets an eoi even for a spurious one
+extern "x86-interrupt" fn pic_2_irq7_handler(
+    _stack_frame: InterruptStackFrame
+) {
+    use x86_64::instructions::port::Port;
+
+    if pic_in_service(PIC_2_COMMAND) & (1 << 7) != 0 {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_2_OFFSET + 7) };
+    } else {
+        unsafe { Port::<u8>::new(PIC_1_COMMAND).write(PIC_EOI) };
     }
-}
\ No newline at end of file
+}
@@ -1,6 +1,7 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
 // imports
 extern crate alloc; // rust alloc
@@ -8,7 +9,8 @@ use x86_64::VirtAddr; // virtual address struct
 use core::panic::PanicInfo; // panic info struct
 use crate::{
     bootinfo::BootInfo, // bootinfo struct
-    memory::BootInfoFrameAllocator, // boot info frame allocator
+    cmdline::LogLevel, // command line log level
+    memory::frame::GlobalFrameAllocator, // physical frame allocator
     task::{ // tasks and executor
         Task, 
         keyboard,
@@ -18,17 +20,27 @@ use crate::{
 
 // modules
 pub mod interrupts; // interrupt handling
+pub mod exceptions; // cpu exception handlers
 pub mod serial; // serial output
 pub mod vga; // vga output
 pub mod gdt; // gdt handling
 pub mod bootinfo; // boot info sent to _start()
+pub mod multiboot; // multiboot2 info parsing
+pub mod cmdline; // kernel command line options
 pub mod memory; // memory management
 pub mod allocator; // memory allocator
 pub mod task; // async tasks
 pub mod timer; // PIT timer
+pub mod time; // monotonic clock
 pub mod system; // system helper functions
+pub mod backtrace; // stack walking
+pub mod acpi; // acpi tables
+pub mod apic; // local apic and io apic
 
 
+// size of the stack the kernel runs on after boot
+const KERNEL_STACK_SIZE: u64 = 64 * 1024;
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
@@ -44,22 +56,68 @@ fn init() {
 }
 
 #[unsafe(no_mangle)] // dont mangle the name of this function
-pub extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+pub extern "C" fn _start(multiboot_info_addr: u64) -> ! {
     // clear screen
     vgaclear!();
 
+    // build boot info from the multiboot2 structure grub left us
+    let boot_info: &'static BootInfo = unsafe { multiboot::init(multiboot_info_addr) };
+    cmdline::init();
+
+    if cmdline::loglevel() >= LogLevel::Debug {
+        println!("cmdline: {}", cmdline::raw());
+        for region in boot_info.memory_regions.iter() {
+            println!("memory: {:#012x} - {:#012x} {:?}", region.start, region.end, region.kind);
+        }
+        if let Some(ramdisk) = boot_info.ramdisk_addr.into_option() {
+            println!("ramdisk: {:#x}, {} bytes", ramdisk, boot_info.ramdisk_len);
+        }
+        if let Some(rsdp) = boot_info.rsdp_addr.into_option() {
+            println!("acpi: rsdp at {:#x}", rsdp);
+        }
+    }
+
     // initialize important things like gdt and interrupts
     init();
 
-    // initialize heap
+    // set up the frame allocator and map all physical memory, then initialize heap
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_regions)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    unsafe { memory::frame::init(&boot_info.memory_regions) };
+    unsafe { memory::physmap::init(&boot_info.memory_regions, &mut GlobalFrameAllocator) };
+    unsafe { memory::init(phys_mem_offset) };
+    vga::remap();
+    gdt::init_stacks();
+    allocator::init_heap()
         .expect("heap initialization failed");
 
+    let acpi = acpi::init();
+
+    // move interrupts from the pics to the apics if the firmware describes them
+    if acpi && apic::init() && cmdline::loglevel() >= LogLevel::Debug {
+        let lapic = apic::local_apic().unwrap();
+        println!("apic: local apic {} (version {:#x}) at {:#x}", lapic.id(), lapic.version(), lapic.phys);
+        apic::io_apics(|ioapic| {
+            println!("apic: io apic {} at {:#x}, gsis {}..{}",
+                ioapic.id, ioapic.phys, ioapic.gsi_base, ioapic.gsi_base + ioapic.inputs);
+        });
+    }
+
+    let clock = time::init();
+    if cmdline::loglevel() >= LogLevel::Debug {
+        println!("time: using the {} clock", clock.name());
+        if let (Some(hz), Some(source)) = (timer::tsc::frequency(), timer::tsc::source()) {
+            println!("time: tsc at {} kHz from {}{}", hz / 1000, source.name(),
+                if timer::tsc::invariant() { ", invariant" } else { "" });
+        }
+    }
+
+    // leave the boot stack for one with a guard page
+    let stack = memory::stack::allocate(KERNEL_STACK_SIZE, "kernel stack")
+        .expect("no memory for the kernel stack");
+    unsafe { stack.switch_to(kernel_main) }
+} // fn _start
+
+extern "C" fn kernel_main() -> ! {
     // initialize keyboard driver
     keyboard::init_keyboard_stream();
 
@@ -1,139 +0,0 @@
-use x86_64::{
-    VirtAddr,
-    PhysAddr,
-    structures::paging::{
-        FrameAllocator, 
-        OffsetPageTable, 
-        PageTable, 
-        PhysFrame, 
-        Size4KiB
-    }
-};
-use crate::bootinfo::{
-    MemoryRegion, 
-    MemoryRegionKind,
-};
-
-
-// Initialize a new OffsetPageTable.
-//
-// This function is unsafe because the caller must guarantee that the
-// complete physical memory is mapped to virtual memory at the passed
-// `physical_memory_offset`. Also, this function must be only called once
-// to avoid aliasing `&mut` references (which is undefined behavior).
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    unsafe {
-        let level_4_table = active_level_4_table(physical_memory_offset);
-        OffsetPageTable::new(level_4_table, physical_memory_offset)
-    }
-}
-
-// Returns a mutable reference to the active level 4 table.
-//
-// This function is unsafe because the caller must guarantee that the
-// complete physical memory is mapped to virtual memory at the passed
-// `physical_memory_offset`. Also, this function must be only called once
-// to avoid aliasing `&mut` references (which is undefined behavior).
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
-    -> &'static mut PageTable
-{
-    use x86_64::registers::control::Cr3;
-
-    let (level_4_table_frame, _) = Cr3::read();
-
-    let phys = level_4_table_frame.start_address();
-    let virt = physical_memory_offset + phys.as_u64();
-    let page_table_str: *mut PageTable = virt.as_mut_ptr();
-
-    unsafe { &mut *page_table_str }
-}
-
-// Translates the given virtual address to the mapped physical address, or
-// `None` if the address is not mapped.
-//
-// This function is unsafe because the caller must guarantee that the
-// complete physical memory is mapped to virtual memory at the passed
-// `physical_memory_offset`.
-pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
-    -> Option<PhysAddr>
-{
-    translate_addr_inner(addr, physical_memory_offset)
-}
-// Private function that is called by `translate_addr`.
-//
-// This function is safe to limit the scope of `unsafe` because Rust treats
-// the whole body of unsafe functions as an unsafe block. This function must
-// only be reachable through `unsafe fn` from outside of this module.
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
-    -> Option<PhysAddr>
-{
-    use x86_64::structures::paging::page_table::FrameError;
-    use x86_64::registers::control::Cr3;
-
-    // read the active level 4 frame from the CR3 register
-    let (level_4_table_frame, _) = Cr3::read();
-
-    let table_indexes = [
-        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
-    ];
-    let mut frame = level_4_table_frame;
-
-    // traverse the multi-level page table
-    for &index in &table_indexes {
-        // convert the frame into a page table reference
-        let virt = physical_memory_offset + frame.start_address().as_u64();
-        let table_ptr: *const PageTable = virt.as_ptr();
-        let table = unsafe {&*table_ptr};
-
-        // read the page table entry and update 'frame'
-        let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            // huge pages
-            Err(FrameError::HugeFrame) => {
-                let phys = frame.start_address()
-                    + u64::from(addr.as_u64() & 0x1F_FFFF);
-                return Some(phys);
-            }
-        };
-    }
-
-    // calculate the physical address by adding the page offset
-    Some(frame.start_address() + u64::from(addr.page_offset()))
-} // fn translate_addr_inner
-
-// A FrameAllocator that returns usable frames from the bootloader's memory map.
-
-pub struct BootInfoFrameAllocator {
-    regions: &'static [MemoryRegion], // slice of regions
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    /// # Safety
-    /// Caller must guarantee that all frames marked usable are actually free.
-    pub unsafe fn init(regions: &'static [MemoryRegion]) -> Self {
-        BootInfoFrameAllocator {
-            regions,
-            next: 0,
-        }
-    }
-
-    /// Returns an iterator over all usable frames in all regions
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
-        self.regions
-            .iter()
-            .filter(|r| r.kind == MemoryRegionKind::Usable) // use your MemoryRegionKind
-            .flat_map(|r| (r.start..r.end).step_by(4096)) // addresses in 4KiB steps
-            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
\ No newline at end of file
@@ -0,0 +1,77 @@
+// physically contiguous buffers for device dma
+//
+// buffers come straight from the buddy frame allocator, so they are always
+// physically contiguous and aligned to their own size rounded up to a power
+// of two. the cpu side goes through the physical map, which is fine on x86
+// because dma is cache coherent.
+
+use core::slice;
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PhysFrame};
+use super::{
+    PHYS_OFFSET,
+    frame::{FRAME_ALLOCATOR, FRAME_SIZE, MAX_ORDER},
+};
+
+pub use super::frame::Zone;
+
+/// Largest buffer `alloc` can return, one maximum order buddy block.
+pub const MAX_DMA_SIZE: usize = (FRAME_SIZE as usize) << MAX_ORDER;
+
+/// A physically contiguous, zeroed buffer. Freed on drop.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    /// Address to give to the device.
+    pub phys: PhysAddr,
+    /// Address for the cpu.
+    pub virt: VirtAddr,
+    /// Length in bytes, as requested.
+    pub len: usize,
+}
+
+impl DmaBuffer {
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.len) }
+    }
+
+    fn frames(&self) -> usize {
+        self.len.div_ceil(FRAME_SIZE as usize)
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let start = PhysFrame::containing_address(self.phys);
+        unsafe { FRAME_ALLOCATOR.lock().deallocate_contiguous(start, self.frames()) };
+    }
+}
+
+/// Allocates a physically contiguous buffer of `len` bytes whose physical
+/// address is aligned to `align` bytes (a power of two) and lies entirely
+/// inside `zone`.
+///
+/// Returns `None` if `len` is 0 or larger than `MAX_DMA_SIZE`, or if the zone
+/// has no free range that big.
+pub fn alloc(len: usize, align: usize, zone: Zone) -> Option<DmaBuffer> {
+    if len == 0 || len > MAX_DMA_SIZE || !align.is_power_of_two() {
+        return None;
+    }
+
+    let count = len.div_ceil(FRAME_SIZE as usize);
+    let align_frames = align.div_ceil(FRAME_SIZE as usize);
+    let start = FRAME_ALLOCATOR
+        .lock()
+        .allocate_contiguous_in(count, align_frames, zone)?;
+
+    let phys = start.start_address();
+    let mut buffer = DmaBuffer {
+        phys,
+        virt: VirtAddr::new(PHYS_OFFSET + phys.as_u64()),
+        len,
+    };
+    buffer.as_mut_slice().fill(0);
+    Some(buffer)
+}
@@ -0,0 +1,130 @@
+// page fault resolution
+//
+// the page fault handler decodes the fault and hands it to each registered
+// resolver in turn. a resolver either fixes the fault (maps the page in),
+// declines it because it is none of its business, or declares it fatal.
+// when nobody resolves a fault the handler prints a kernel oops.
+//
+// the built in resolvers are always there, in this order:
+//
+//   guard page      faults below a kernel stack are overflows, see `stack`
+//   copy on write   writes to shared pages of file backed vm areas
+//   demand paging   first touch of a page in a vm area
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::{VirtAddr, structures::idt::PageFaultErrorCode};
+use super::{stack, vm};
+
+/// A decoded page fault.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFault {
+    /// The address that was accessed, from CR2.
+    pub addr: VirtAddr,
+    /// The instruction that faulted.
+    pub ip: VirtAddr,
+    /// The page was present, so the access itself was not allowed.
+    pub present: bool,
+    pub write: bool,
+    pub user: bool,
+    pub instruction_fetch: bool,
+    /// A page table entry had a reserved bit set.
+    pub reserved_bit: bool,
+}
+
+impl PageFault {
+    pub fn new(addr: VirtAddr, ip: VirtAddr, error: PageFaultErrorCode) -> Self {
+        PageFault {
+            addr,
+            ip,
+            present: error.contains(PageFaultErrorCode::PROTECTION_VIOLATION),
+            write: error.contains(PageFaultErrorCode::CAUSED_BY_WRITE),
+            user: error.contains(PageFaultErrorCode::USER_MODE),
+            instruction_fetch: error.contains(PageFaultErrorCode::INSTRUCTION_FETCH),
+            reserved_bit: error.contains(PageFaultErrorCode::MALFORMED_TABLE),
+        }
+    }
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = match (self.instruction_fetch, self.write) {
+            (true, _) => "instruction fetch",
+            (false, true) => "write",
+            (false, false) => "read",
+        };
+        let page = if self.present { "present" } else { "non-present" };
+        let mode = if self.user { "user" } else { "kernel" };
+        write!(f, "{} {} of {} page at {:#x} from {:#x}", mode, access, page, self.addr, self.ip)?;
+        if self.reserved_bit {
+            write!(f, ", reserved bit set")?;
+        }
+        Ok(())
+    }
+}
+
+/// What a resolver made of a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// Fixed, the faulting instruction can run again.
+    Resolved,
+    /// Not this resolver's business.
+    Declined,
+    /// The fault can't be fixed, for this reason.
+    Fatal(&'static str),
+}
+
+pub type Resolver = fn(&PageFault) -> Resolution;
+
+const MAX_RESOLVERS: usize = 8;
+
+static RESOLVERS: Mutex<[Option<(&'static str, Resolver)>; MAX_RESOLVERS]> = Mutex::new([
+    Some(("guard page", stack::guard_page)),
+    Some(("copy on write", vm::copy_on_write)),
+    Some(("demand paging", vm::demand_page)),
+    None,
+    None,
+    None,
+    None,
+    None,
+]);
+
+/// Adds a resolver after the built in ones. Returns `false` if there is no
+/// room for another.
+///
+/// Resolvers run in the page fault handler: they must not allocate from the
+/// heap or fault themselves.
+pub fn register_resolver(name: &'static str, resolver: Resolver) -> bool {
+    let mut resolvers = RESOLVERS.lock();
+    match resolvers.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some((name, resolver));
+            true
+        }
+        None => false,
+    }
+}
+
+/// Runs the resolvers until one of them fixes the fault or gives up on it.
+/// Returns why the fault could not be resolved.
+pub fn resolve(fault: &PageFault) -> Result<(), &'static str> {
+    if fault.reserved_bit {
+        return Err("corrupted page table");
+    }
+
+    // copy the list so a resolver can register another one
+    let resolvers = *RESOLVERS.lock();
+    for (_, resolver) in resolvers.iter().flatten() {
+        match resolver(fault) {
+            Resolution::Resolved => return Ok(()),
+            Resolution::Declined => {}
+            Resolution::Fatal(reason) => return Err(reason),
+        }
+    }
+
+    Err(match (fault.present, fault.user) {
+        (_, true) => "user access to kernel memory",
+        (true, _) => "access not allowed by the page",
+        (false, _) => "access to unmapped memory",
+    })
+}
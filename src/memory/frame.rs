@@ -0,0 +1,417 @@
+// physical frame allocator
+//
+// a binary buddy allocator over all usable memory from the boot memory map.
+// blocks go from a single 4 KiB frame (order 0) up to 2^MAX_ORDER frames, and
+// a block of order k is always aligned to 2^k frames.
+//
+// free blocks are tracked with one bitmap per order: bit i of order k is set
+// when the block of 2^k frames starting at frame i << k is free. freeing a
+// block merges it with its buddy as long as the buddy is free too. nothing is
+// ever written into free memory, so frames above what the page tables map can
+// be managed before they are reachable.
+//
+// one more bitmap, with a bit per frame, marks the frames the allocator
+// manages at all. a free of anything else, like a reserved hole, the kernel
+// image or the bitmaps themselves, is reported and ignored, and so is a free
+// of a frame that is already free.
+//
+// the bitmaps are carved out of the first usable region big enough to hold
+// them and accessed through the physical map.
+//
+// memory is split into zones for devices that can't address all of it.
+// ordinary allocations stay out of the isa dma zone while there is anything
+// else left, so it is still around when a driver needs it.
+
+use core::{ops::Range, slice};
+use spin::Mutex;
+use x86_64::{
+    PhysAddr,
+    structures::paging::{
+        FrameAllocator,
+        FrameDeallocator,
+        PhysFrame,
+        Size4KiB,
+    },
+};
+use crate::bootinfo::{MemoryRegion, MemoryRegionKind};
+use super::PHYS_OFFSET;
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Largest block order, 2^10 frames = 4 MiB.
+pub const MAX_ORDER: usize = 10;
+
+const ORDERS: usize = MAX_ORDER + 1;
+
+/// Physical address ranges some devices are limited to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// Below 16 MiB, for isa dma.
+    Dma,
+    /// Below 4 GiB, for 32-bit devices.
+    Dma32,
+    /// Anywhere.
+    Normal,
+}
+
+impl Zone {
+    /// End address (exclusive) of the zone.
+    pub fn limit(self) -> u64 {
+        match self {
+            Zone::Dma => 16 << 20,
+            Zone::Dma32 => 4 << 30,
+            Zone::Normal => u64::MAX,
+        }
+    }
+
+    // frame indexes in the zone
+    fn frames(self) -> Range<usize> {
+        0..frame_index(self.limit())
+    }
+}
+
+// the boot page tables only reach the first 1 GiB, the bitmaps must live there
+const BOOT_MAPPED_END: u64 = 1 << 30;
+
+pub struct BuddyFrameAllocator {
+    bitmap: *mut u64,            // all orders, back to back
+    offsets: [usize; ORDERS],    // first word of each order in `bitmap`
+    words: [usize; ORDERS],      // words per order
+    managed: usize,              // first word of the managed frames bitmap
+    hints: [usize; ORDERS],      // no free block below this word, per order
+    end: usize,                  // frame index the bitmaps cover up to
+    total_frames: usize,         // usable frames
+    free_frames: usize,
+}
+
+// the bitmap is only ever touched with the lock held
+unsafe impl Send for BuddyFrameAllocator {}
+
+impl BuddyFrameAllocator {
+    pub const fn empty() -> Self {
+        BuddyFrameAllocator {
+            bitmap: core::ptr::null_mut(),
+            offsets: [0; ORDERS],
+            words: [0; ORDERS],
+            managed: 0,
+            hints: [0; ORDERS],
+            end: 0,
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// # Safety
+    /// All memory marked usable in `regions` must really be free and the first
+    /// 1 GiB must be mapped at `PHYS_OFFSET`.
+    pub unsafe fn init(&mut self, regions: &[MemoryRegion]) {
+        let usable = || regions.iter().filter(|r| r.kind == MemoryRegionKind::Usable);
+
+        let frames = (usable().map(|r| r.end).max().unwrap_or(0) / FRAME_SIZE) as usize;
+        self.end = frames;
+
+        let mut total_words = 0;
+        for order in 0..ORDERS {
+            // one spare bit so the buddy of the last block is always in range
+            self.offsets[order] = total_words;
+            self.words[order] = ((frames >> order) + 2).div_ceil(64);
+            total_words += self.words[order];
+        }
+        self.managed = total_words;
+        total_words += frames.div_ceil(64);
+        let bitmap_bytes = (total_words as u64 * 8).next_multiple_of(FRAME_SIZE);
+
+        let bitmap_region = usable()
+            .find(|r| r.end - r.start >= bitmap_bytes && r.start + bitmap_bytes <= BOOT_MAPPED_END)
+            .expect("frame allocator: no room for the bitmaps");
+        let bitmap_start = bitmap_region.start;
+
+        self.bitmap = (PHYS_OFFSET + bitmap_start) as *mut u64;
+        unsafe { slice::from_raw_parts_mut(self.bitmap, total_words).fill(0) };
+
+        for region in usable() {
+            let mut start = region.start;
+            if start == bitmap_start {
+                start += bitmap_bytes;
+            }
+            let (first, end) = (frame_index(start), frame_index(region.end));
+            for frame in first..end {
+                self.set_managed(frame);
+            }
+            self.total_frames += end - first;
+            self.free_range(first, end);
+        }
+    }
+
+    fn bitmap(&mut self, order: usize) -> &mut [u64] {
+        unsafe {
+            slice::from_raw_parts_mut(self.bitmap.add(self.offsets[order]), self.words[order])
+        }
+    }
+
+    fn managed_bitmap(&mut self) -> &mut [u64] {
+        unsafe { slice::from_raw_parts_mut(self.bitmap.add(self.managed), self.end.div_ceil(64)) }
+    }
+
+    fn set_managed(&mut self, frame: usize) {
+        self.managed_bitmap()[frame / 64] |= 1 << (frame % 64);
+    }
+
+    fn is_managed(&mut self, frame: usize) -> bool {
+        self.managed_bitmap()[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    // whether the frame is part of a free block of any order
+    fn is_free(&mut self, frame: usize) -> bool {
+        (0..ORDERS).any(|order| self.test(order, frame >> order))
+    }
+
+    // why `count` frames from frame index `start` can't be freed, if so
+    fn check_free(&mut self, start: usize, count: usize) -> Result<(), &'static str> {
+        if start.checked_add(count).is_none_or(|end| end > self.end) {
+            return Err("outside of ram");
+        }
+        for frame in start..start + count {
+            if !self.is_managed(frame) {
+                return Err("not memory the allocator hands out");
+            }
+            if self.is_free(frame) {
+                return Err("already free");
+            }
+        }
+        Ok(())
+    }
+
+    fn test(&mut self, order: usize, block: usize) -> bool {
+        self.bitmap(order)[block / 64] & (1 << (block % 64)) != 0
+    }
+
+    fn set(&mut self, order: usize, block: usize) {
+        self.bitmap(order)[block / 64] |= 1 << (block % 64);
+        self.hints[order] = self.hints[order].min(block / 64);
+    }
+
+    fn clear(&mut self, order: usize, block: usize) {
+        self.bitmap(order)[block / 64] &= !(1 << (block % 64));
+    }
+
+    // lowest free block of exactly this order, starting at block `from`
+    fn find(&mut self, order: usize, from: usize) -> Option<usize> {
+        let hint = self.hints[order];
+        let first_word = hint.max(from / 64);
+        let bitmap = self.bitmap(order);
+
+        let (word, bits) = bitmap
+            .iter()
+            .enumerate()
+            .skip(first_word)
+            .map(|(word, bits)| {
+                // ignore blocks below `from` in the first word
+                if word == from / 64 { (word, bits & (!0 << (from % 64))) } else { (word, *bits) }
+            })
+            .find(|(_, bits)| *bits != 0)?;
+
+        // everything below the hint is known to be empty
+        if from <= hint * 64 {
+            self.hints[order] = word;
+        }
+        Some(word * 64 + bits.trailing_zeros() as usize)
+    }
+
+    /// Allocates a block of 2^order frames that lies within the frame index
+    /// range `frames`, returns its first frame index.
+    ///
+    /// Always takes the lowest free block that is big enough, so early
+    /// allocations come from low memory the boot page tables can reach.
+    fn allocate_block(&mut self, order: usize, frames: Range<usize>) -> Option<usize> {
+        let (mut block, mut current) = (order..ORDERS)
+            .filter_map(|o| self.find(o, frames.start.div_ceil(1 << o)).map(|block| (block, o)))
+            .min_by_key(|&(block, o)| block << o)?;
+        if (block << current) + (1 << order) > frames.end {
+            return None;
+        }
+        self.clear(current, block);
+
+        // split down, putting the upper halves back
+        while current > order {
+            current -= 1;
+            block *= 2;
+            self.set(current, block + 1);
+        }
+
+        self.free_frames -= 1 << order;
+        Some(block << order)
+    }
+
+    /// Frees the block of 2^order frames starting at frame index `frame`,
+    /// merging it with its buddies.
+    fn free_block(&mut self, frame: usize, mut order: usize) {
+        self.free_frames += 1 << order;
+
+        let mut block = frame >> order;
+        while order < MAX_ORDER && self.test(order, block ^ 1) {
+            self.clear(order, block ^ 1);
+            block /= 2;
+            order += 1;
+        }
+        self.set(order, block);
+    }
+
+    // frees [start, end) as the largest aligned blocks that fit
+    fn free_range(&mut self, mut start: usize, end: usize) {
+        while start < end {
+            let mut order = (start.trailing_zeros() as usize).min(MAX_ORDER);
+            while start + (1 << order) > end {
+                order -= 1;
+            }
+            self.free_block(start, order);
+            start += 1 << order;
+        }
+    }
+
+    // ordinary allocations: above the isa dma zone if possible
+    fn allocate_normal(&mut self, order: usize) -> Option<usize> {
+        let above_dma = Zone::Dma.frames().end..usize::MAX;
+        self.allocate_block(order, above_dma)
+            .or_else(|| self.allocate_block(order, Zone::Normal.frames()))
+    }
+
+    /// Allocates `count` physically contiguous frames, aligned to `align`
+    /// frames (a power of two).
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        let order = order_for(count.max(align));
+        if count == 0 || order > MAX_ORDER {
+            return None;
+        }
+
+        let start = self.allocate_normal(order)?;
+        // hand back the tail we don't need
+        self.free_range(start + count, start + (1 << order));
+
+        Some(frame_at(start))
+    }
+
+    /// Like `allocate_contiguous`, but the whole range lies inside `zone`.
+    pub fn allocate_contiguous_in(
+        &mut self,
+        count: usize,
+        align: usize,
+        zone: Zone,
+    ) -> Option<PhysFrame> {
+        let order = order_for(count.max(align));
+        if count == 0 || order > MAX_ORDER {
+            return None;
+        }
+
+        let start = self.allocate_block(order, zone.frames())?;
+        self.free_range(start + count, start + (1 << order));
+
+        Some(frame_at(start))
+    }
+
+    /// Frees frames from `allocate_contiguous`.
+    ///
+    /// # Safety
+    /// The frames must have been allocated with the same `count` and must
+    /// not be used afterwards.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let index = frame_index(start.start_address().as_u64());
+        if let Err(reason) = self.check_free(index, count) {
+            crate::eprintln!("frame allocator: freeing {} frames at {:?}: {}", count, start, reason);
+            return;
+        }
+        self.free_range(index, index + count);
+    }
+
+    /// Frames the allocator manages: usable memory, less the bitmaps.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Free blocks of exactly 2^order frames.
+    pub fn free_blocks(&mut self, order: usize) -> usize {
+        self.bitmap(order).iter().map(|bits| bits.count_ones() as usize).sum()
+    }
+
+    /// Free frames below the end of `zone`.
+    pub fn free_frames_in(&mut self, zone: Zone) -> usize {
+        let end = zone.frames().end;
+        (0..ORDERS)
+            .map(|order| {
+                let blocks = end >> order;
+                let bitmap = self.bitmap(order);
+                let words = bitmap.len().min(blocks / 64);
+                let mut free: usize = bitmap[..words].iter().map(|bits| bits.count_ones() as usize).sum();
+                // blocks in the last, partly covered word
+                if words < bitmap.len() && !blocks.is_multiple_of(64) {
+                    free += (bitmap[words] & ((1 << (blocks % 64)) - 1)).count_ones() as usize;
+                }
+                free << order
+            })
+            .sum()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.allocate_normal(0).map(frame_at)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = frame_index(frame.start_address().as_u64());
+        if let Err(reason) = self.check_free(index, 1) {
+            crate::eprintln!("frame allocator: freeing {:?}: {}", frame, reason);
+            return;
+        }
+        self.free_block(index, 0);
+    }
+}
+
+fn frame_index(addr: u64) -> usize {
+    (addr / FRAME_SIZE) as usize
+}
+
+fn frame_at(index: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+}
+
+// smallest order whose blocks hold `frames` frames
+fn order_for(frames: usize) -> usize {
+    frames.next_power_of_two().trailing_zeros() as usize
+}
+
+/// The kernel's physical memory manager.
+pub static FRAME_ALLOCATOR: Mutex<BuddyFrameAllocator> = Mutex::new(BuddyFrameAllocator::empty());
+
+/// Builds the frame allocator from the boot memory map.
+///
+/// # Safety
+/// Must be called once, see `BuddyFrameAllocator::init`.
+pub unsafe fn init(regions: &[MemoryRegion]) {
+    unsafe { FRAME_ALLOCATOR.lock().init(regions) };
+}
+
+/// Handle to `FRAME_ALLOCATOR` for APIs that want a `FrameAllocator`.
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        FRAME_ALLOCATOR.lock().allocate_frame()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        unsafe { FRAME_ALLOCATOR.lock().deallocate_frame(frame) };
+    }
+}
@@ -0,0 +1,95 @@
+// kernel image mapping with per-section permissions
+//
+// the boot page tables map the whole kernel image writable and executable.
+// here we map each section with only the access it needs, using the section
+// bounds from linker.ld, so a stray write into code or rodata faults instead
+// of silently corrupting it, and data can never be executed:
+//
+//   .text              read-only, executable
+//   .rodata            read-only, no-execute
+//   .data and .bss     read-write, no-execute
+
+use x86_64::{
+    PhysAddr,
+    VirtAddr,
+    structures::paging::{
+        FrameAllocator,
+        Mapper,
+        Page,
+        PageTableFlags,
+        PhysFrame,
+        Size4KiB,
+        mapper::MapToError,
+    },
+};
+use super::layout::KERNEL_BASE;
+
+// virtual bounds of the kernel sections, from linker.ld
+unsafe extern "C" {
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// A section of the kernel image.
+#[derive(Debug, Clone, Copy)]
+pub struct Section {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+}
+
+/// The kernel sections and the flags they are mapped with.
+pub fn sections() -> [Section; 3] {
+    use PageTableFlags as F;
+
+    let addr = |symbol: *const u8| VirtAddr::from_ptr(symbol);
+    [
+        Section {
+            name: ".text",
+            start: addr(&raw const __text_start),
+            end: addr(&raw const __text_end),
+            flags: F::PRESENT,
+        },
+        Section {
+            name: ".rodata",
+            start: addr(&raw const __rodata_start),
+            end: addr(&raw const __rodata_end),
+            flags: F::PRESENT | F::NO_EXECUTE,
+        },
+        Section {
+            name: ".data",
+            start: addr(&raw const __data_start),
+            end: addr(&raw const __data_end),
+            flags: F::PRESENT | F::WRITABLE | F::NO_EXECUTE,
+        },
+    ]
+}
+
+/// Maps the kernel sections into `mapper` with 4 KiB pages.
+///
+/// The mapper must not be active yet, nothing is flushed.
+pub fn map(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    for section in sections() {
+        if section.start == section.end {
+            continue;
+        }
+
+        let first = Page::<Size4KiB>::containing_address(section.start);
+        let last = Page::<Size4KiB>::containing_address(section.end - 1u64);
+
+        for page in Page::range_inclusive(first, last) {
+            let phys = PhysAddr::new(page.start_address().as_u64() - KERNEL_BASE);
+            let frame = PhysFrame::containing_address(phys);
+            unsafe { mapper.map_to(page, frame, section.flags, frame_allocator)?.ignore() };
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+// virtual address space layout
+//
+// the lower half is left free for userspace and per-process address spaces,
+// everything the kernel uses lives in the higher half:
+//
+//   0x0000_0000_0000_0000 - 0x0000_7FFF_FFFF_FFFF   user space      128 TiB
+//   0xFFFF_8000_0000_0000 - 0xFFFF_BFFF_FFFF_FFFF   physical map     64 TiB
+//   0xFFFF_C000_0000_0000 - 0xFFFF_C0FF_FFFF_FFFF   kernel heap       1 TiB
+//   0xFFFF_C800_0000_0000 - 0xFFFF_C8FF_FFFF_FFFF   vm areas          1 TiB
+//   0xFFFF_D000_0000_0000 - 0xFFFF_D0FF_FFFF_FFFF   kernel stacks     1 TiB
+//   0xFFFF_E000_0000_0000 - 0xFFFF_E0FF_FFFF_FFFF   mmio              1 TiB
+//   0xFFFF_FFFF_8000_0000 - 0xFFFF_FFFF_FFFF_FFFF   kernel image      2 GiB
+//
+// the kernel image region must match KERNEL_BASE in linker.ld and the
+// boot page tables in main.asm.
+
+/// A fixed region of the kernel's virtual address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Region {
+    pub const fn new(start: u64, size: u64) -> Self {
+        Region { start, size }
+    }
+
+    /// End address (exclusive) of the region.
+    pub const fn end(&self) -> u64 {
+        self.start.wrapping_add(self.size)
+    }
+
+    pub const fn contains(&self, addr: u64) -> bool {
+        addr >= self.start && addr - self.start < self.size
+    }
+}
+
+const TIB: u64 = 1 << 40;
+
+/// Free for userspace.
+pub const USER_SPACE: Region = Region::new(0, 0x0000_8000_0000_0000);
+
+/// All of physical memory, mapped 1:1 at an offset.
+pub const PHYS_MAP: Region = Region::new(0xFFFF_8000_0000_0000, 64 * TIB);
+
+/// The kernel heap, see `allocator`.
+pub const HEAP: Region = Region::new(0xFFFF_C000_0000_0000, TIB);
+
+/// Demand paged kernel mappings, see `memory::vm`.
+pub const VM: Region = Region::new(0xFFFF_C800_0000_0000, TIB);
+
+/// Kernel stacks.
+pub const KERNEL_STACKS: Region = Region::new(0xFFFF_D000_0000_0000, TIB);
+
+/// Device memory.
+pub const MMIO: Region = Region::new(0xFFFF_E000_0000_0000, TIB);
+
+/// The kernel image, linked at `KERNEL_BASE`.
+pub const KERNEL_IMAGE: Region = Region::new(KERNEL_BASE, 2 << 30);
+
+/// Virtual address the kernel is linked at, physical 0 maps here.
+pub const KERNEL_BASE: u64 = 0xFFFF_FFFF_8000_0000;
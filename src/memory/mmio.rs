@@ -0,0 +1,58 @@
+// device memory
+//
+// `map_mmio` maps a physical range into the mmio window with the requested
+// cache mode. the mapping is a vm area like any other, but its pages are
+// mapped right away, since device registers are often touched from interrupt
+// handlers. the physical map also covers low memory (vga, the bios area) but
+// maps it write-back, so devices should always go through here.
+
+use core::slice;
+use x86_64::{PhysAddr, VirtAddr};
+use super::{
+    layout,
+    pat::CacheMode,
+    vm::{self, Backing, Protection, VmError},
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Maps `len` bytes of device memory at `phys` uncached.
+pub fn map_mmio(phys: PhysAddr, len: usize) -> Result<&'static mut [u8], VmError> {
+    map_mmio_with(phys, len, CacheMode::Uncached)
+}
+
+/// Maps `len` bytes of device memory at `phys` with the given cache mode.
+///
+/// The range is rounded out to whole pages and an unmapped guard page is left
+/// in front of it.
+pub fn map_mmio_with(phys: PhysAddr, len: usize, cache: CacheMode) -> Result<&'static mut [u8], VmError> {
+    if len == 0 {
+        return Err(VmError::Unaligned);
+    }
+
+    let base = phys.align_down(PAGE_SIZE);
+    let offset = phys - base;
+    let size = (offset + len as u64).next_multiple_of(PAGE_SIZE);
+
+    let backing = Backing::Mmio(base, cache);
+    let start = vm::allocate(layout::MMIO, size, PAGE_SIZE, Protection::READ_WRITE, backing, "mmio")?;
+    if vm::populate(start).is_err() {
+        // only page tables can run out here, and the area is no use without them
+        let _ = unsafe { vm::unmap(start) };
+        return Err(VmError::OutOfMemory);
+    }
+
+    Ok(unsafe { slice::from_raw_parts_mut((start + offset).as_mut_ptr(), len) })
+}
+
+/// Removes a mapping made by `map_mmio`.
+///
+/// # Safety
+/// Nothing may use the mapping afterwards.
+pub unsafe fn unmap_mmio(mapping: &'static mut [u8]) -> Result<(), VmError> {
+    let addr = VirtAddr::from_ptr(mapping.as_ptr());
+    let vma = vm::find(addr)
+        .filter(|vma| matches!(vma.backing, Backing::Mmio(..)))
+        .ok_or(VmError::NotFound)?;
+    unsafe { vm::unmap(vma.start) }
+}
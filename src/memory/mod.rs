@@ -0,0 +1,227 @@
+use x86_64::{
+    VirtAddr,
+    PhysAddr,
+    structures::paging::{
+        FrameAllocator,
+        FrameDeallocator,
+        Mapper,
+        OffsetPageTable, 
+        Page,
+        PageTable, 
+        PageTableFlags,
+        Size4KiB,
+        mapper::MapToError,
+    }
+};
+use spin::Mutex;
+use frame::GlobalFrameAllocator;
+
+pub mod layout; // virtual address space layout
+pub mod physmap; // all physical memory mapped at PHYS_OFFSET
+pub mod image; // kernel image sections
+pub mod frame; // physical frame allocator
+pub mod dma; // physically contiguous dma buffers
+pub mod vm; // virtual memory areas and demand paging
+pub mod pat; // page attribute table
+pub mod mmio; // device memory mappings
+pub mod stack; // guarded kernel stacks
+pub mod fault; // page fault resolvers
+
+// virtual address at which all physical memory is mapped
+pub const PHYS_OFFSET: u64 = layout::PHYS_MAP.start;
+
+// the kernel page tables, set up by `init`
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+// Initialize the kernel's OffsetPageTable.
+//
+// This function is unsafe because the caller must guarantee that the
+// complete physical memory is mapped to virtual memory at the passed
+// `physical_memory_offset`. Also, this function must be only called once
+// to avoid aliasing `&mut` references (which is undefined behavior).
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    pat::init();
+    let mapper = unsafe {
+        let level_4_table = active_level_4_table(physical_memory_offset);
+        OffsetPageTable::new(level_4_table, physical_memory_offset)
+    };
+    *MAPPER.lock() = Some(mapper);
+}
+
+/// Whether `init` has run, so pages can be mapped.
+pub fn initialized() -> bool {
+    MAPPER.lock().is_some()
+}
+
+// Runs `f` with the kernel page tables locked.
+//
+// `f` must not allocate from the heap: growing the heap maps pages too, and
+// would deadlock on the lock.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    let mut mapper = MAPPER.lock();
+    f(mapper.as_mut().expect("memory: page tables not initialized"))
+}
+
+// Maps `size` bytes at `start` (page aligned) to freshly allocated frames.
+//
+// On failure nothing stays mapped.
+pub fn map_range(start: VirtAddr, size: u64, flags: PageTableFlags)
+    -> Result<(), MapToError<Size4KiB>>
+{
+    let first = Page::<Size4KiB>::containing_address(start);
+    let pages = size.div_ceil(Page::<Size4KiB>::SIZE);
+
+    with_mapper(|mapper| {
+        let mut frame_allocator = GlobalFrameAllocator;
+
+        for (i, page) in Page::range(first, first + pages).enumerate() {
+            let result = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)
+                .and_then(|frame| unsafe {
+                    mapper.map_to(page, frame, flags, &mut frame_allocator).inspect_err(|_| {
+                        frame_allocator.deallocate_frame(frame);
+                    })
+                });
+
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    unsafe { unmap_pages(mapper, first, i as u64) };
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Unmaps `size` bytes at `start` and frees the frames behind them.
+///
+/// # Safety
+/// Nothing may use the range afterwards, and the frames must have come from
+/// the frame allocator.
+pub unsafe fn unmap_range(start: VirtAddr, size: u64) {
+    let first = Page::<Size4KiB>::containing_address(start);
+    let pages = size.div_ceil(Page::<Size4KiB>::SIZE);
+
+    with_mapper(|mapper| unsafe { unmap_pages(mapper, first, pages) });
+}
+
+unsafe fn unmap_pages(mapper: &mut OffsetPageTable, first: Page, count: u64) {
+    for page in Page::range(first, first + count) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+// Returns a mutable reference to the active level 4 table.
+//
+// This function is unsafe because the caller must guarantee that the
+// complete physical memory is mapped to virtual memory at the passed
+// `physical_memory_offset`. Also, this function must be only called once
+// to avoid aliasing `&mut` references (which is undefined behavior).
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+    -> &'static mut PageTable
+{
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_str: *mut PageTable = virt.as_mut_ptr();
+
+    unsafe { &mut *page_table_str }
+}
+
+// Translates the given virtual address to the mapped physical address, or
+// `None` if the address is not mapped.
+//
+// This function is unsafe because the caller must guarantee that the
+// complete physical memory is mapped to virtual memory at the passed
+// `physical_memory_offset`.
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    translate_addr_inner(addr, physical_memory_offset)
+}
+// Private function that is called by `translate_addr`.
+//
+// This function is safe to limit the scope of `unsafe` because Rust treats
+// the whole body of unsafe functions as an unsafe block. This function must
+// only be reachable through `unsafe fn` from outside of this module.
+fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
+    -> Option<PhysAddr>
+{
+    walk_inner(addr, physical_memory_offset, |_| {})
+}
+
+/// One step of a page table walk.
+#[derive(Debug, Clone, Copy)]
+pub struct WalkStep {
+    /// 4 for the level 4 table down to 1.
+    pub level: u8,
+    /// Physical address of the table.
+    pub table: PhysAddr,
+    /// Index of the entry in the table.
+    pub index: u16,
+    pub addr: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+/// Walks the active page tables for `addr`, calling `visit` with every entry
+/// on the way, and returns the physical address it maps to.
+pub fn page_walk(addr: VirtAddr, visit: impl FnMut(&WalkStep)) -> Option<PhysAddr> {
+    walk_inner(addr, VirtAddr::new(PHYS_OFFSET), visit)
+}
+
+fn walk_inner(addr: VirtAddr, physical_memory_offset: VirtAddr, mut visit: impl FnMut(&WalkStep))
+    -> Option<PhysAddr>
+{
+    use x86_64::structures::paging::page_table::FrameError;
+    use x86_64::registers::control::Cr3;
+
+    // read the active level 4 frame from the CR3 register
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut frame = level_4_table_frame;
+
+    // bytes covered by an entry at each level, for huge pages
+    let entry_sizes = [0, 1 << 30, 1 << 21, 1 << 12];
+
+    // traverse the multi-level page table
+    for (level, (&index, &entry_size)) in table_indexes.iter().zip(&entry_sizes).enumerate() {
+        // convert the frame into a page table reference
+        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let table_ptr: *const PageTable = virt.as_ptr();
+        let table = unsafe {&*table_ptr};
+
+        // read the page table entry and update 'frame'
+        let entry = &table[index];
+        visit(&WalkStep {
+            level: 4 - level as u8,
+            table: frame.start_address(),
+            index: u16::from(index),
+            addr: entry.addr(),
+            flags: entry.flags(),
+        });
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) => return None,
+            // huge pages, 1 GiB at level 3 and 2 MiB at level 2
+            Err(FrameError::HugeFrame) => {
+                let phys = entry.addr() + (addr.as_u64() & (entry_size - 1));
+                return Some(phys);
+            }
+        };
+    }
+
+    // calculate the physical address by adding the page offset
+    Some(frame.start_address() + u64::from(addr.page_offset()))
+} // fn walk_inner
@@ -0,0 +1,79 @@
+// page attribute table
+//
+// the memory type of a page comes from the PAT entry picked by its PWT, PCD
+// and PAT bits. the power on table has no write-combining entry, so `init`
+// reprograms it:
+//
+//   index  PAT PCD PWT  type
+//   0       0   0   0   write-back
+//   1       0   0   1   write-combining (power on: write-through)
+//   2       0   1   0   uncached minus
+//   3       0   1   1   uncached
+//   4-7     1   x   x   same as power on
+//
+// entries 0, 2 and 3 keep their power on meaning, so page tables built before
+// `init` keep working.
+
+use raw_cpuid::CpuId;
+use x86_64::{
+    registers::{control::Cr3, model_specific::Msr},
+    structures::paging::PageTableFlags,
+};
+
+const IA32_PAT: u32 = 0x277;
+
+// memory type encodings
+const WB: u64 = 0x06;
+const WC: u64 = 0x01;
+const UC_MINUS: u64 = 0x07;
+const UC: u64 = 0x00;
+const WT: u64 = 0x04;
+
+const TABLE: [u64; 8] = [WB, WC, UC_MINUS, UC, WB, WT, UC_MINUS, UC];
+
+/// How the cpu may cache a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Normal memory.
+    WriteBack,
+    /// Writes are buffered and sent in bursts, reads are uncached. For
+    /// framebuffers.
+    WriteCombining,
+    /// Every access goes to the device. For registers.
+    Uncached,
+}
+
+impl CacheMode {
+    /// Page table flags selecting this mode.
+    pub fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            // without a PAT, write-through is the closest there is
+            CacheMode::WriteCombining => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Programs the PAT as described above, if the cpu has one.
+pub fn init() {
+    let has_pat = CpuId::new()
+        .get_feature_info()
+        .is_some_and(|f| f.has_pat());
+    if !has_pat {
+        return;
+    }
+
+    let value = TABLE
+        .iter()
+        .enumerate()
+        .fold(0, |value, (i, &typ)| value | typ << (i * 8));
+
+    unsafe {
+        // caches and tlb must not hold lines of the old types
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+        Msr::new(IA32_PAT).write(value);
+        let (frame, flags) = Cr3::read();
+        Cr3::write(frame, flags);
+    }
+}
@@ -0,0 +1,146 @@
+// mapping of all physical memory at PHYS_OFFSET
+//
+// the boot page tables from main.asm only alias the first 1 GiB at
+// PHYS_OFFSET. once we know the real memory map we build our own level 4
+// table that maps every byte of RAM there with huge pages, and switch to it.
+// the new table also maps the kernel image section by section (see
+// `memory::image`) and nothing else, so switching to it drops the identity
+// map and frees up the lower half.
+//
+// the first 2 MiB are mapped with small pages, so the legacy hole from
+// 0xA0000 to 0xFFFFF (vga memory and option roms) can be mapped uncached,
+// the same as `mmio` maps it. two mappings of a page with different memory
+// types are undefined behavior.
+
+use x86_64::{
+    PhysAddr,
+    VirtAddr,
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator,
+        Mapper,
+        OffsetPageTable,
+        Page,
+        PageSize,
+        PageTable,
+        PageTableFlags,
+        PhysFrame,
+        Size1GiB,
+        Size2MiB,
+        Size4KiB,
+        mapper::MapToError,
+    },
+};
+use core::sync::atomic::{AtomicU64, Ordering};
+use raw_cpuid::CpuId;
+use crate::bootinfo::{MemoryRegion, MemoryRegionKind};
+use super::{PHYS_OFFSET, image, pat::CacheMode};
+
+// e820 types that are backed by RAM: acpi reclaimable and acpi nvs
+const ACPI_RECLAIMABLE: u32 = 3;
+const ACPI_NVS: u32 = 4;
+
+// the legacy hole below 1 MiB, device memory
+const LEGACY_HOLE_START: u64 = 0xA0000;
+const LEGACY_HOLE_END: u64 = 0x100000;
+
+// end of the physical map, the boot page tables map the first 1 GiB
+static END: AtomicU64 = AtomicU64::new(1 << 30);
+
+/// Physical addresses below this are mapped at `PHYS_OFFSET`.
+pub fn end() -> u64 {
+    END.load(Ordering::Relaxed)
+}
+
+fn is_ram(kind: MemoryRegionKind) -> bool {
+    matches!(
+        kind,
+        MemoryRegionKind::Usable
+            | MemoryRegionKind::Bootloader
+            | MemoryRegionKind::UnknownBios(ACPI_RECLAIMABLE)
+            | MemoryRegionKind::UnknownBios(ACPI_NVS)
+    )
+}
+
+/// Highest physical address (exclusive) backed by RAM.
+pub fn max_physical_address(regions: &[MemoryRegion]) -> u64 {
+    regions
+        .iter()
+        .filter(|r| is_ram(r.kind))
+        .map(|r| r.end)
+        .max()
+        .unwrap_or(0)
+}
+
+// map [start, end) at PHYS_OFFSET using pages of size S
+fn map_range<S: PageSize>(
+    mapper: &mut OffsetPageTable,
+    start: u64,
+    end: u64,
+    cache: CacheMode,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+{
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache.flags();
+
+    for phys in (start..end).step_by(S::SIZE as usize) {
+        let page = Page::<S>::containing_address(VirtAddr::new(PHYS_OFFSET + phys));
+        let frame = PhysFrame::<S>::containing_address(PhysAddr::new(phys));
+
+        // the new table is not active yet, nothing to flush
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.ignore() };
+    }
+    Ok(())
+}
+
+/// Builds page tables mapping all RAM at `PHYS_OFFSET` and the kernel image at
+/// `KERNEL_BASE`, and switches to them. The physical map is never executable.
+///
+/// # Safety
+/// Must be called once, while the boot page tables are active, and nothing
+/// may use the identity map afterwards. The frames handed out by
+/// `frame_allocator` must lie in the first 1 GiB, which is all the boot page
+/// tables can reach.
+pub unsafe fn init(
+    regions: &[MemoryRegion],
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) {
+    let use_1gib_pages = CpuId::new()
+        .get_extended_processor_and_feature_identifiers()
+        .is_some_and(|f| f.has_1gib_pages());
+    let page_size = if use_1gib_pages { Size1GiB::SIZE } else { Size2MiB::SIZE };
+    let end = max_physical_address(regions).div_ceil(page_size) * page_size;
+
+    let (_, cr3_flags) = Cr3::read();
+
+    let l4_frame = frame_allocator
+        .allocate_frame()
+        .expect("physmap: no frame for the level 4 table");
+    let l4: &'static mut PageTable =
+        unsafe { &mut *VirtAddr::new(PHYS_OFFSET + l4_frame.start_address().as_u64()).as_mut_ptr() };
+    l4.zero();
+
+    let mut mapper = unsafe { OffsetPageTable::new(l4, VirtAddr::new(PHYS_OFFSET)) };
+    image::map(&mut mapper, frame_allocator)
+        .expect("physmap: failed to map the kernel image");
+
+    // small pages up to 2 MiB, 2 MiB pages up to the first huge page, then
+    // huge pages
+    let small_end = Size2MiB::SIZE;
+    let large_end = if use_1gib_pages { Size1GiB::SIZE } else { end };
+    let wb = CacheMode::WriteBack;
+    let mapped = map_range::<Size4KiB>(&mut mapper, 0, LEGACY_HOLE_START, wb, frame_allocator).is_ok()
+        && map_range::<Size4KiB>(&mut mapper, LEGACY_HOLE_START, LEGACY_HOLE_END, CacheMode::Uncached, frame_allocator).is_ok()
+        && map_range::<Size4KiB>(&mut mapper, LEGACY_HOLE_END, small_end, wb, frame_allocator).is_ok()
+        && map_range::<Size2MiB>(&mut mapper, small_end, large_end, wb, frame_allocator).is_ok()
+        && map_range::<Size1GiB>(&mut mapper, large_end, end, wb, frame_allocator).is_ok();
+    assert!(mapped, "physmap: failed to map physical memory");
+
+    unsafe { Cr3::write(l4_frame, cr3_flags) };
+    END.store(end, Ordering::Relaxed);
+}
@@ -0,0 +1,82 @@
+// kernel stacks
+//
+// every stack is a vm area in the kernel stacks region with an unmapped
+// guard page below it. running off the bottom of a stack faults on the guard
+// page instead of quietly overwriting whatever lies below, and the fault
+// handlers use `overflowed` to say whose stack it was. stacks are mapped
+// right away, a stack that needs a page fault to grow can't take one.
+//
+// only the very first boot stacks in main.asm and main64.asm are plain
+// arrays, they are left as soon as memory is set up.
+
+use core::arch::asm;
+use x86_64::VirtAddr;
+use super::{
+    fault::{PageFault, Resolution},
+    layout,
+    vm::{self, Backing, Protection, Vma, VmError},
+};
+
+/// Unmapped bytes below every stack.
+pub const GUARD_SIZE: u64 = 4096;
+
+/// A mapped kernel stack. Stacks grow down, so `top` is where it starts.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    pub bottom: VirtAddr,
+    pub top: VirtAddr,
+}
+
+impl KernelStack {
+    /// Continues on this stack by calling `entry`. The current stack is
+    /// never used again.
+    ///
+    /// # Safety
+    /// Nothing on the current stack may be referenced after the switch.
+    pub unsafe fn switch_to(&self, entry: extern "C" fn() -> !) -> ! {
+        unsafe {
+            asm!(
+                "mov rsp, {top}",
+                "xor rbp, rbp", // end of the frame pointer chain
+                "call {entry}",
+                "ud2",
+                top = in(reg) self.top.as_u64(),
+                entry = in(reg) entry,
+                options(noreturn),
+            )
+        }
+    }
+}
+
+/// Allocates a stack of `size` bytes (a multiple of the page size) with a
+/// guard page below it.
+pub fn allocate(size: u64, name: &'static str) -> Result<KernelStack, VmError> {
+    let (protection, backing) = (Protection::READ_WRITE, Backing::Anonymous);
+    let bottom = vm::allocate(layout::KERNEL_STACKS, size, GUARD_SIZE, protection, backing, name)?;
+    if vm::populate(bottom).is_err() {
+        let _ = unsafe { vm::unmap(bottom) };
+        return Err(VmError::OutOfMemory);
+    }
+    Ok(KernelStack { bottom, top: bottom + size })
+}
+
+/// The stack whose guard page `addr` is in, if any. Safe to call from fault
+/// handlers: `None` if the area list is locked.
+pub fn overflowed(addr: VirtAddr) -> Option<Vma> {
+    if !layout::KERNEL_STACKS.contains(addr.as_u64()) {
+        return None;
+    }
+    vm::find_for_fault(addr + GUARD_SIZE)
+        .ok()
+        .flatten()
+        .filter(|vma| addr < vma.start)
+}
+
+/// Page fault resolver: a fault in a guard page is a stack overflow, which
+/// can't be fixed.
+pub fn guard_page(fault: &PageFault) -> Resolution {
+    match overflowed(fault.addr) {
+        Some(_) => Resolution::Fatal("kernel stack overflow"),
+        None => Resolution::Declined,
+    }
+}
@@ -0,0 +1,447 @@
+// virtual memory areas
+//
+// a vm area (vma) is a reserved range of kernel virtual addresses with
+// permissions and a backing. reserving one maps nothing: pages are mapped in
+// by the page fault handler the first time they are touched.
+//
+//   anonymous   zeroed frames from the frame allocator
+//   physical    a fixed physical range, e.g. a firmware table
+//   mmio        like physical, with a cache mode for device memory
+//   file        a private copy of some bytes in memory, e.g. a boot module
+//
+// whole pages of file data in the physical map are not copied on first
+// touch: the page is mapped read only straight to the data, and copied on
+// the first write, see `copy_on_write`.
+//
+// the list of areas is a fixed array rather than a heap collection, so the
+// page fault handler needs no allocation to look at it. it is still behind a
+// spin lock: a fault taken while the lock is held can't be resolved and is
+// fatal rather than a deadlock, see `find_for_fault`.
+
+use core::slice;
+use spin::Mutex;
+use x86_64::{
+    PhysAddr,
+    VirtAddr,
+    structures::{
+        paging::{
+            FrameAllocator,
+            FrameDeallocator,
+            Mapper,
+            Page,
+            PageTableFlags,
+            PhysFrame,
+            Size4KiB,
+            mapper::{MapToError, UnmapError},
+        },
+    },
+};
+use super::{
+    PHYS_OFFSET,
+    fault::{PageFault, Resolution},
+    frame::GlobalFrameAllocator,
+    layout::{self, Region},
+    pat::CacheMode,
+    with_mapper,
+};
+
+const PAGE_SIZE: u64 = Page::<Size4KiB>::SIZE;
+
+// most areas that can exist at once
+const MAX_AREAS: usize = 128;
+
+static AREAS: Mutex<[Option<Vma>; MAX_AREAS]> = Mutex::new([None; MAX_AREAS]);
+
+/// What a vm area may be used for. Areas are always readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protection {
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Protection {
+    pub const READ: Protection = Protection { write: false, execute: false };
+    pub const READ_WRITE: Protection = Protection { write: true, execute: false };
+    pub const READ_EXECUTE: Protection = Protection { write: false, execute: true };
+}
+
+/// Where the pages of a vm area come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed memory.
+    Anonymous,
+    /// Physical memory starting at this address.
+    Physical(PhysAddr),
+    /// Device memory starting at this address, see `memory::mmio`.
+    Mmio(PhysAddr, CacheMode),
+    /// A private copy of `data`, zero filled past its end.
+    File(&'static [u8]),
+}
+
+/// A reserved range of virtual addresses.
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub protection: Protection,
+    pub backing: Backing,
+    pub name: &'static str,
+    /// Pages currently mapped in.
+    pub resident: u64,
+}
+
+impl Vma {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end()
+    }
+
+    fn flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.protection.write {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.protection.execute {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        if let Backing::Mmio(_, cache) = self.backing {
+            flags |= cache.flags();
+        }
+        flags
+    }
+
+    // whether `frame`, mapped at `page`, was allocated by us rather than
+    // borrowed from the backing
+    fn owns_frame(&self, page: Page, frame: PhysFrame) -> bool {
+        match self.backing {
+            Backing::Anonymous => true,
+            Backing::File(_) => self.shared_frame(page) != Some(frame),
+            Backing::Physical(_) | Backing::Mmio(..) => false,
+        }
+    }
+
+    // the frame holding the file data of `page`, if it can be mapped as is:
+    // a whole page of data, page aligned and in the physical map
+    fn shared_frame(&self, page: Page) -> Option<PhysFrame> {
+        let Backing::File(data) = self.backing else {
+            return None;
+        };
+        let offset = page.start_address() - self.start;
+        let addr = data.as_ptr() as u64 + offset;
+        let whole = offset + PAGE_SIZE <= data.len() as u64;
+        let in_physmap = layout::PHYS_MAP.contains(addr) && layout::PHYS_MAP.contains(addr + PAGE_SIZE - 1);
+        if !whole || !in_physmap || !addr.is_multiple_of(PAGE_SIZE) {
+            return None;
+        }
+        Some(PhysFrame::containing_address(PhysAddr::new(addr - PHYS_OFFSET)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    /// Start or size not page aligned, or a size of 0.
+    Unaligned,
+    /// The range overlaps an existing area.
+    Overlap,
+    /// No free range that big in the region.
+    NoSpace,
+    /// The area list is full.
+    TooManyAreas,
+    /// No area starts at that address.
+    NotFound,
+    /// No frames to map the area in with.
+    OutOfMemory,
+}
+
+/// Why pages of an area could not be mapped in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address is not in any area.
+    NoArea,
+    /// No frame to map in.
+    OutOfMemory,
+    /// The area list was locked by the code that faulted.
+    Locked,
+}
+
+/// Reserves `size` bytes at `start` as a new area.
+pub fn map(
+    start: VirtAddr,
+    size: u64,
+    protection: Protection,
+    backing: Backing,
+    name: &'static str,
+) -> Result<VirtAddr, VmError> {
+    if size == 0 || !start.is_aligned(PAGE_SIZE) || !size.is_multiple_of(PAGE_SIZE) {
+        return Err(VmError::Unaligned);
+    }
+
+    let mut areas = AREAS.lock();
+    if areas.iter().flatten().any(|vma| vma.overlaps(start, start + size)) {
+        return Err(VmError::Overlap);
+    }
+    let slot = areas
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(VmError::TooManyAreas)?;
+
+    *slot = Some(Vma { start, size, protection, backing, name, resident: 0 });
+    Ok(start)
+}
+
+/// Reserves `size` bytes anywhere in `region`, leaving `gap` unreserved bytes
+/// before the area, and returns where it ended up.
+pub fn allocate(
+    region: Region,
+    size: u64,
+    gap: u64,
+    protection: Protection,
+    backing: Backing,
+    name: &'static str,
+) -> Result<VirtAddr, VmError> {
+    if size == 0 || !size.is_multiple_of(PAGE_SIZE) || !gap.is_multiple_of(PAGE_SIZE) {
+        return Err(VmError::Unaligned);
+    }
+
+    // first fit: try the region start, then the end of every area in it
+    let start = {
+        let areas = AREAS.lock();
+        let candidates = core::iter::once(region.start).chain(
+            areas.iter().flatten().map(|vma| vma.end().as_u64()).filter(|&end| region.contains(end)),
+        );
+        candidates
+            .map(|candidate| candidate + gap)
+            .filter(|&start| start + size <= region.end())
+            .filter(|&start| {
+                let (start, end) = (VirtAddr::new(start), VirtAddr::new(start + size));
+                !areas.iter().flatten().any(|vma| vma.overlaps(start - gap, end))
+            })
+            .min()
+            .ok_or(VmError::NoSpace)?
+    };
+
+    map(VirtAddr::new(start), size, protection, backing, name)
+}
+
+/// Removes the area starting at `start`, unmapping its pages and freeing
+/// the frames it allocated.
+///
+/// # Safety
+/// Nothing may use the area afterwards.
+pub unsafe fn unmap(start: VirtAddr) -> Result<(), VmError> {
+    let vma = {
+        let mut areas = AREAS.lock();
+        let slot = areas
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|vma| vma.start == start))
+            .ok_or(VmError::NotFound)?;
+        slot.take().unwrap()
+    };
+
+    let first = Page::<Size4KiB>::containing_address(vma.start);
+    let pages = vma.size / PAGE_SIZE;
+    with_mapper(|mapper| {
+        for page in Page::range(first, first + pages) {
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    free_if_owned(&vma, page, frame);
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(err) => panic!("vm: unmapping {:?} of {}: {:?}", page, vma.name, err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The area containing `addr`, if any.
+pub fn find(addr: VirtAddr) -> Option<Vma> {
+    AREAS.lock().iter().flatten().find(|vma| vma.contains(addr)).copied()
+}
+
+/// Like `find`, for the page fault path, where spinning on the lock would
+/// never end if the faulting code holds it.
+pub fn find_for_fault(addr: VirtAddr) -> Result<Option<Vma>, FaultError> {
+    let areas = AREAS.try_lock().ok_or(FaultError::Locked)?;
+    Ok(areas.iter().flatten().find(|vma| vma.contains(addr)).copied())
+}
+
+/// Calls `f` for every area, lowest address first.
+pub fn areas(mut f: impl FnMut(&Vma)) {
+    let mut areas = *AREAS.lock();
+    areas.sort_unstable_by_key(|slot| slot.map_or(u64::MAX, |vma| vma.start.as_u64()));
+    for vma in areas.iter().flatten() {
+        f(vma);
+    }
+}
+
+/// Page fault resolver: maps in the page behind a faulting address if an
+/// area allows the access.
+pub fn demand_page(fault: &PageFault) -> Resolution {
+    if fault.present {
+        return Resolution::Declined;
+    }
+    let vma = match find_for_fault(fault.addr) {
+        Ok(Some(vma)) => vma,
+        Ok(None) => return Resolution::Declined,
+        Err(_) => return Resolution::Fatal("page fault with the vm areas locked"),
+    };
+    if let Err(reason) = check_access(&vma, fault) {
+        return Resolution::Fatal(reason);
+    }
+
+    match map_page(&vma, Page::containing_address(fault.addr), fault.write) {
+        Ok(()) => Resolution::Resolved,
+        Err(_) => Resolution::Fatal("out of memory"),
+    }
+}
+
+/// Page fault resolver: gives a file backed area its own copy of a shared
+/// page on the first write to it.
+pub fn copy_on_write(fault: &PageFault) -> Resolution {
+    if !fault.present || !fault.write {
+        return Resolution::Declined;
+    }
+    let vma = match find_for_fault(fault.addr) {
+        Ok(Some(vma)) => vma,
+        Ok(None) => return Resolution::Declined,
+        Err(_) => return Resolution::Fatal("page fault with the vm areas locked"),
+    };
+    let page = Page::containing_address(fault.addr);
+    let Some(shared) = vma.shared_frame(page) else {
+        return Resolution::Declined;
+    };
+    if !vma.protection.write {
+        return Resolution::Fatal("write to a read-only area");
+    }
+
+    let virt = (PHYS_OFFSET + shared.start_address().as_u64()) as *const u8;
+    let data = unsafe { slice::from_raw_parts(virt, PAGE_SIZE as usize) };
+    let Ok(copy) = new_frame(data) else {
+        return Resolution::Fatal("out of memory");
+    };
+
+    // swap the shared frame for the copy, the shared one is not ours to free
+    let swapped = with_mapper(|mapper| match mapper.unmap(page) {
+        Ok((frame, flush)) if frame == shared => {
+            flush.flush();
+            let mut frame_allocator = GlobalFrameAllocator;
+            unsafe { mapper.map_to(page, copy, vma.flags(), &mut frame_allocator) }
+                .map(|flush| flush.flush())
+                .is_ok()
+        }
+        // already copied by someone else, put it back
+        Ok((frame, flush)) => {
+            flush.flush();
+            let mut frame_allocator = GlobalFrameAllocator;
+            let _ = unsafe { mapper.map_to(page, frame, vma.flags(), &mut frame_allocator) }
+                .map(|flush| flush.flush());
+            false
+        }
+        Err(_) => false,
+    });
+    if !swapped {
+        unsafe { GlobalFrameAllocator.deallocate_frame(copy) };
+    }
+    Resolution::Resolved
+}
+
+// why `vma` does not allow the access that faulted, if it doesn't
+fn check_access(vma: &Vma, fault: &PageFault) -> Result<(), &'static str> {
+    if fault.user {
+        Err("user access to a kernel area")
+    } else if fault.write && !vma.protection.write {
+        Err("write to a read-only area")
+    } else if fault.instruction_fetch && !vma.protection.execute {
+        Err("instruction fetch from a non-executable area")
+    } else {
+        Ok(())
+    }
+}
+
+/// Maps in every page of the area starting at `start` right away.
+pub fn populate(start: VirtAddr) -> Result<(), FaultError> {
+    let vma = find(start).filter(|vma| vma.start == start).ok_or(FaultError::NoArea)?;
+
+    let first = Page::<Size4KiB>::containing_address(vma.start);
+    for page in Page::range(first, first + vma.size / PAGE_SIZE) {
+        map_page(&vma, page, true)?;
+    }
+    Ok(())
+}
+
+// maps in `page` of `vma`, `write` says whether it is about to be written
+fn map_page(vma: &Vma, page: Page, write: bool) -> Result<(), FaultError> {
+    let offset = page.start_address() - vma.start;
+
+    let mut flags = vma.flags();
+    let frame = match vma.backing {
+        Backing::Physical(base) | Backing::Mmio(base, _) => PhysFrame::containing_address(base + offset),
+        Backing::Anonymous => new_frame(&[])?,
+        Backing::File(data) => match vma.shared_frame(page).filter(|_| !write) {
+            // read only until the first write copies it
+            Some(shared) => {
+                flags.remove(PageTableFlags::WRITABLE);
+                shared
+            }
+            None => {
+                let from = (offset as usize).min(data.len());
+                let to = (offset + PAGE_SIZE).min(data.len() as u64) as usize;
+                new_frame(&data[from..to])?
+            }
+        },
+    };
+
+    let mapped = with_mapper(|mapper| {
+        let mut frame_allocator = GlobalFrameAllocator;
+        unsafe { mapper.map_to(page, frame, flags, &mut frame_allocator) }.map(|flush| flush.flush())
+    });
+
+    match mapped {
+        Ok(()) => {
+            count_resident(vma.start);
+            Ok(())
+        }
+        // someone else got there first
+        Err(MapToError::PageAlreadyMapped(_)) => {
+            free_if_owned(vma, page, frame);
+            Ok(())
+        }
+        Err(_) => {
+            free_if_owned(vma, page, frame);
+            Err(FaultError::OutOfMemory)
+        }
+    }
+}
+
+// a zeroed frame starting with `data`
+fn new_frame(data: &[u8]) -> Result<PhysFrame, FaultError> {
+    let frame = GlobalFrameAllocator.allocate_frame().ok_or(FaultError::OutOfMemory)?;
+
+    let virt = (PHYS_OFFSET + frame.start_address().as_u64()) as *mut u8;
+    let page = unsafe { slice::from_raw_parts_mut(virt, PAGE_SIZE as usize) };
+    page[..data.len()].copy_from_slice(data);
+    page[data.len()..].fill(0);
+    Ok(frame)
+}
+
+fn free_if_owned(vma: &Vma, page: Page, frame: PhysFrame) {
+    if vma.owns_frame(page, frame) {
+        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+    }
+}
+
+fn count_resident(start: VirtAddr) {
+    let mut areas = AREAS.lock();
+    if let Some(vma) = areas.iter_mut().flatten().find(|vma| vma.start == start) {
+        vma.resident += 1;
+    }
+}
@@ -0,0 +1,447 @@
+// multiboot2 boot information parsing
+//
+// grub leaves the physical address of the boot information structure in ebx
+// when it jumps to `start`. the structure is an 8 byte header (total size and
+// a reserved field) followed by a list of 8 byte aligned tags, each starting
+// with a type and a size, and terminated by an end tag of type 0.
+
+use core::{slice, str};
+use conquer_once::spin::OnceCell;
+use crate::{
+    acpi,
+    bootinfo::{
+        BootInfo,
+        MemoryRegion,
+        MemoryRegionKind,
+        MemoryRegions,
+        Optional,
+    },
+    memory::{PHYS_OFFSET, layout::KERNEL_BASE, physmap},
+};
+
+// tag types
+pub const TAG_END: u32 = 0;
+pub const TAG_CMDLINE: u32 = 1;
+pub const TAG_BOOTLOADER_NAME: u32 = 2;
+pub const TAG_MODULE: u32 = 3;
+pub const TAG_MEMORY_MAP: u32 = 6;
+pub const TAG_ACPI_OLD: u32 = 14;
+pub const TAG_ACPI_NEW: u32 = 15;
+
+// memory map entry types
+const MEMORY_AVAILABLE: u32 = 1;
+
+const PAGE_SIZE: u64 = 4096;
+
+// the first MiB holds the real mode ivt, bios data area and ebda
+const LOW_MEMORY_END: u64 = 0x100000;
+
+// maximum number of regions the memory map can be split into
+const MAX_MEMORY_REGIONS: usize = 64;
+
+// physical bounds of the kernel image, from linker.ld
+unsafe extern "C" {
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+}
+
+/// A single tag of the boot information structure.
+#[derive(Debug, Clone, Copy)]
+pub struct Tag {
+    pub typ: u32,
+    /// The bytes following the tag header.
+    pub data: &'static [u8],
+}
+
+impl Tag {
+    fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_le_bytes(self.data[offset..offset + 8].try_into().unwrap())
+    }
+
+    // nul terminated utf-8 string starting at offset
+    fn str_at(&self, offset: usize) -> Option<&'static str> {
+        let bytes = &self.data[offset..];
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        str::from_utf8(&bytes[..len]).ok()
+    }
+}
+
+/// The multiboot2 boot information structure handed to us by grub.
+#[derive(Debug, Clone, Copy)]
+pub struct MultibootInfo {
+    phys: u64,
+    data: &'static [u8],
+}
+
+impl MultibootInfo {
+    /// Panics if the structure is not below `physmap::end()`, which at boot
+    /// is the end of the boot mapping: reading it would fault before there
+    /// is anything to report the fault.
+    ///
+    /// # Safety
+    /// `phys` must be the address grub passed in ebx.
+    pub unsafe fn load(phys: u64) -> Self {
+        let reachable = |end: u64| end <= physmap::end();
+        assert!(reachable(phys + 8), "multiboot: info at {:#x} is beyond the physical map", phys);
+
+        let ptr = (PHYS_OFFSET + phys) as *const u8;
+        let total_size = unsafe { (ptr as *const u32).read() } as usize;
+        assert!(
+            reachable(phys + total_size as u64),
+            "multiboot: info at {:#x}, {} bytes, is beyond the physical map", phys, total_size
+        );
+
+        MultibootInfo {
+            phys,
+            data: unsafe { slice::from_raw_parts(ptr, total_size) },
+        }
+    }
+
+    /// Physical start address of the structure.
+    pub fn start_address(&self) -> u64 {
+        self.phys
+    }
+
+    /// Physical end address (exclusive) of the structure.
+    pub fn end_address(&self) -> u64 {
+        self.phys + self.data.len() as u64
+    }
+
+    pub fn tags(&self) -> TagIter {
+        TagIter { data: self.data, offset: 8 }
+    }
+
+    /// Returns the first tag of the given type.
+    pub fn tag(&self, typ: u32) -> Option<Tag> {
+        self.tags().find(|tag| tag.typ == typ)
+    }
+
+    /// The kernel command line from `grub.cfg`, if any.
+    pub fn command_line(&self) -> Option<&'static str> {
+        self.tag(TAG_CMDLINE).and_then(|tag| tag.str_at(0))
+    }
+
+    pub fn bootloader_name(&self) -> Option<&'static str> {
+        self.tag(TAG_BOOTLOADER_NAME).and_then(|tag| tag.str_at(0))
+    }
+
+    /// Boot modules loaded with `module2` in `grub.cfg`.
+    pub fn modules(&self) -> impl Iterator<Item = Module> {
+        self.tags()
+            .filter(|tag| tag.typ == TAG_MODULE)
+            .map(|tag| Module {
+                start: tag.u32_at(0) as u64,
+                end: tag.u32_at(4) as u64,
+                cmdline: tag.str_at(8).unwrap_or(""),
+            })
+    }
+
+    /// Grub's copy of the acpi rsdp, the acpi 2.0 one if there is one.
+    pub fn rsdp(&self) -> Option<&'static [u8]> {
+        self.tag(TAG_ACPI_NEW)
+            .or_else(|| self.tag(TAG_ACPI_OLD))
+            .map(|tag| tag.data)
+    }
+
+    /// The firmware memory map.
+    pub fn memory_map(&self) -> Option<MemoryMapIter> {
+        self.tag(TAG_MEMORY_MAP).map(|tag| MemoryMapIter {
+            entry_size: tag.u32_at(0) as usize,
+            // skip entry_size and entry_version
+            offset: 8,
+            tag,
+        })
+    }
+}
+
+pub struct TagIter {
+    data: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for TagIter {
+    type Item = Tag;
+
+    fn next(&mut self) -> Option<Tag> {
+        if self.offset + 8 > self.data.len() {
+            return None;
+        }
+
+        let header = &self.data[self.offset..];
+        let typ = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+
+        // a corrupt size would loop forever or run off the end
+        if typ == TAG_END || size < 8 || self.offset + size > self.data.len() {
+            return None;
+        }
+
+        let tag = Tag {
+            typ,
+            data: &self.data[self.offset + 8..self.offset + size],
+        };
+
+        // tags are padded to 8 bytes
+        self.offset += (size + 7) & !7;
+        Some(tag)
+    }
+}
+
+/// A boot module, e.g. an initrd.
+#[derive(Debug, Clone, Copy)]
+pub struct Module {
+    /// Physical start address of the module.
+    pub start: u64,
+    /// Physical end address (exclusive) of the module.
+    pub end: u64,
+    /// Whatever followed the path on the `module2` line.
+    pub cmdline: &'static str,
+}
+
+impl Module {
+    pub fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The module contents through the physical memory mapping, `None` while
+    /// the mapping doesn't reach that far yet.
+    pub fn bytes(&self) -> Option<&'static [u8]> {
+        if self.end > physmap::end() {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts((PHYS_OFFSET + self.start) as *const u8, self.len() as usize) })
+    }
+}
+
+/// An entry of the multiboot2 memory map tag.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMapEntry {
+    pub base: u64,
+    pub len: u64,
+    pub typ: u32,
+}
+
+impl MemoryMapEntry {
+    pub fn kind(&self) -> MemoryRegionKind {
+        match self.typ {
+            MEMORY_AVAILABLE => MemoryRegionKind::Usable,
+            other => MemoryRegionKind::UnknownBios(other),
+        }
+    }
+}
+
+pub struct MemoryMapIter {
+    tag: Tag,
+    entry_size: usize,
+    offset: usize,
+}
+
+impl Iterator for MemoryMapIter {
+    type Item = MemoryMapEntry;
+
+    fn next(&mut self) -> Option<MemoryMapEntry> {
+        if self.entry_size < 20 || self.offset + self.entry_size > self.tag.data.len() {
+            return None;
+        }
+
+        let entry = MemoryMapEntry {
+            base: self.tag.u64_at(self.offset),
+            len: self.tag.u64_at(self.offset + 8),
+            typ: self.tag.u32_at(self.offset + 16),
+        };
+        self.offset += self.entry_size;
+        Some(entry)
+    }
+}
+
+// fixed size list of regions, since there is no heap yet
+struct RegionList {
+    regions: &'static mut [MemoryRegion],
+    len: usize,
+}
+
+impl RegionList {
+    fn push(&mut self, region: MemoryRegion) {
+        if region.start >= region.end {
+            return;
+        }
+        if self.len == self.regions.len() {
+            crate::serial_println!("multiboot: too many memory regions, dropping {:#x?}", region);
+            return;
+        }
+        self.regions[self.len] = region;
+        self.len += 1;
+    }
+
+    // sorts the regions and resolves overlaps the firmware left in its map:
+    // any other kind wins over usable, and overlapping or touching usable
+    // regions are merged into one
+    fn normalize(&mut self) {
+        for i in 0..self.len {
+            let region = self.regions[i];
+            if region.kind != MemoryRegionKind::Usable {
+                self.remove_usable(region.start, region.end);
+            }
+        }
+
+        self.regions[..self.len].sort_unstable_by_key(|r| r.start);
+
+        // compact, dropping emptied regions and merging usable ones
+        let mut len: usize = 0;
+        for i in 0..self.len {
+            let region = self.regions[i];
+            if region.start >= region.end {
+                continue;
+            }
+            match len.checked_sub(1).map(|last| &mut self.regions[last]) {
+                Some(last) if last.kind == MemoryRegionKind::Usable
+                    && region.kind == MemoryRegionKind::Usable
+                    && region.start <= last.end =>
+                {
+                    last.end = last.end.max(region.end);
+                }
+                _ => {
+                    self.regions[len] = region;
+                    len += 1;
+                }
+            }
+        }
+        self.len = len;
+    }
+
+    // takes [start, end) out of every usable region, keeping them page aligned
+    fn remove_usable(&mut self, start: u64, end: u64) {
+        let start = start & !(PAGE_SIZE - 1);
+        let end = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        for i in 0..self.len {
+            let region = self.regions[i];
+            if region.kind != MemoryRegionKind::Usable
+                || region.end <= start
+                || region.start >= end
+            {
+                continue;
+            }
+
+            // the part below stays in place, possibly empty, the part above
+            // is added at the end
+            self.regions[i].end = region.end.min(start).max(region.start);
+            self.push(MemoryRegion { start: end, end: region.end, kind: region.kind });
+        }
+    }
+
+    // carve [start, end) out of any usable region and mark it as `kind`
+    fn reserve(&mut self, start: u64, end: u64, kind: MemoryRegionKind) {
+        // keep usable regions page aligned
+        let start = start & !(PAGE_SIZE - 1);
+        let end = (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+        for i in 0..self.len {
+            let region = self.regions[i];
+            if region.kind != MemoryRegionKind::Usable
+                || region.end <= start
+                || region.start >= end
+            {
+                continue;
+            }
+
+            self.regions[i] = MemoryRegion {
+                start: region.start.max(start),
+                end: region.end.min(end),
+                kind,
+            };
+            self.push(MemoryRegion { start: region.start, end: start, kind: region.kind });
+            self.push(MemoryRegion { start: end, end: region.end, kind: region.kind });
+        }
+    }
+
+    fn finish(self) -> MemoryRegions {
+        let regions = &mut self.regions[..self.len];
+        regions.sort_unstable_by_key(|r| r.start);
+        MemoryRegions::from(regions)
+    }
+}
+
+static INFO: OnceCell<MultibootInfo> = OnceCell::uninit();
+static BOOT_INFO: OnceCell<BootInfo> = OnceCell::uninit();
+static mut MEMORY_REGIONS: [MemoryRegion; MAX_MEMORY_REGIONS] =
+    [MemoryRegion::empty(); MAX_MEMORY_REGIONS];
+
+/// Builds the kernel's `BootInfo` from the multiboot2 information structure.
+///
+/// # Safety
+/// Must be called once, with the address grub passed in ebx.
+pub unsafe fn init(multiboot_info_addr: u64) -> &'static BootInfo {
+    let info = unsafe { MultibootInfo::load(multiboot_info_addr) };
+    INFO.try_init_once(|| info)
+        .expect("multiboot: info already initialized");
+
+    let mut regions = RegionList {
+        regions: unsafe {
+            slice::from_raw_parts_mut((&raw mut MEMORY_REGIONS).cast(), MAX_MEMORY_REGIONS)
+        },
+        len: 0,
+    };
+
+    for entry in info.memory_map().expect("multiboot: no memory map tag") {
+        let kind = entry.kind();
+        let (mut start, mut end) = (entry.base, entry.base + entry.len);
+
+        // only hand out whole frames
+        if kind == MemoryRegionKind::Usable {
+            start = (start + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+            end &= !(PAGE_SIZE - 1);
+        }
+        regions.push(MemoryRegion { start, end, kind });
+    }
+    regions.normalize();
+
+    let kernel_start = &raw const __kernel_start as u64;
+    let kernel_end = &raw const __kernel_end as u64;
+
+    // nothing we are still using may be handed out by the frame allocator
+    regions.reserve(0, LOW_MEMORY_END, MemoryRegionKind::Bootloader);
+    regions.reserve(kernel_start, kernel_end, MemoryRegionKind::Bootloader);
+    regions.reserve(info.start_address(), info.end_address(), MemoryRegionKind::Bootloader);
+    for module in info.modules() {
+        regions.reserve(module.start, module.end, MemoryRegionKind::Bootloader);
+    }
+
+    let mut boot_info = BootInfo::new(regions.finish());
+    boot_info.physical_memory_offset = Optional::Some(PHYS_OFFSET);
+    boot_info.kernel_addr = kernel_start;
+    boot_info.kernel_len = kernel_end - kernel_start;
+    boot_info.kernel_image_offset = KERNEL_BASE;
+
+    // the first module is the ramdisk, its address is physical
+    if let Some(ramdisk) = info.modules().next() {
+        boot_info.ramdisk_addr = Optional::Some(ramdisk.start);
+        boot_info.ramdisk_len = ramdisk.len();
+    }
+
+    if let Some(rsdp) = acpi::find_rsdp(&info) {
+        boot_info.rsdp_addr = Optional::Some(rsdp.as_u64());
+    }
+
+    BOOT_INFO.try_init_once(|| boot_info)
+        .expect("multiboot: boot info already initialized");
+    BOOT_INFO.get().unwrap()
+}
+
+/// The boot info built by `init`.
+pub fn boot_info() -> Option<&'static BootInfo> {
+    BOOT_INFO.get()
+}
+
+/// The multiboot2 information structure, once `init` has run.
+pub fn info() -> Option<&'static MultibootInfo> {
+    INFO.get()
+}
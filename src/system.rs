@@ -1,16 +1,117 @@
 use x86_64::instructions::port::Port;
 
+// how long to give each reset method, in port 0x80 writes of about 1 us each
+const RESET_TIMEOUT: usize = 100_000;
+
+// 8042 keyboard controller ports and its pulse reset line command
+const KBC_STATUS: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 0x02;
+const KBC_RESET: u8 = 0xFE;
+
+// reset control register of the chipset, soft then hard reset
+const RESET_CONTROL: u16 = 0xCF9;
+const RESET_CONTROL_SYS_RST: u8 = 0x02;
+const RESET_CONTROL_RST_CPU: u8 = 0x04;
+
+// reboots the machine
+//
+// tries the keyboard controller, the acpi reset register, the chipset reset
+// control register and finally a triple fault, giving each a moment to work.
+// every attempt is logged before it is made, so the last line on the serial
+// port says which method worked.
 pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+
+    // firmware that says there is no 8042 may hang on it
+    if crate::acpi::fadt::get().is_none_or(|fadt| fadt.has_8042()) {
+        crate::eprintln!("reboot: keyboard controller");
+        unsafe { keyboard_controller_reset() };
+        wait();
+    }
+
+    crate::eprintln!("reboot: acpi reset register");
+    if crate::acpi::power::reset() {
+        wait();
+    }
+
+    crate::eprintln!("reboot: reset control register");
+    unsafe { reset_control_reset() };
+    wait();
+
+    crate::eprintln!("reboot: triple fault");
+    triple_fault();
+}
+
+unsafe fn keyboard_controller_reset() {
+    let mut port = Port::<u8>::new(KBC_STATUS);
+    unsafe {
+        // wait until the input buffer is empty, but not forever
+        for _ in 0..RESET_TIMEOUT {
+            if port.read() & KBC_INPUT_FULL == 0 {
+                break;
+            }
+        }
+        port.write(KBC_RESET);
+    }
+}
+
+unsafe fn reset_control_reset() {
+    let mut port = Port::<u8>::new(RESET_CONTROL);
     unsafe {
-        let mut port = Port::<u8>::new(0x64);
+        let value = port.read() & !(RESET_CONTROL_SYS_RST | RESET_CONTROL_RST_CPU);
+        port.write(value | RESET_CONTROL_SYS_RST);
+        io_wait();
+        port.write(value | RESET_CONTROL_SYS_RST | RESET_CONTROL_RST_CPU);
+    }
+}
+
+// with an empty idt any exception turns into a triple fault, which resets
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{DescriptorTablePointer, lidt};
+
+    let idt = DescriptorTablePointer { limit: 0, base: x86_64::VirtAddr::zero() };
+    unsafe {
+        lidt(&idt);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    crate::hlt_loop();
+}
+
+fn wait() {
+    for _ in 0..RESET_TIMEOUT {
+        io_wait();
+    }
+}
+
+// port 0x80 is unused after boot, writing to it takes about a microsecond
+fn io_wait() {
+    unsafe { Port::<u8>::new(0x80).write(0) };
+}
 
-        // send reset command
-        loop {
-            // wait until buffer is empty
-            while port.read() & 0x02 != 0 {}
-            port.write(0xFE);
+// emulator ports that power off when the value is written to them. on real
+// hardware these may be chipset registers, so they are only written when
+// running under a hypervisor
+const SHUTDOWN_PORTS: [(u16, u16); 2] = [
+    (0x604, 0x2000),  // qemu
+    (0xB004, 0x2000), // bochs and older qemu
+];
+
+pub fn shutdown() -> ! {
+    crate::println!("shutting down");
+
+    // acpi first, it only comes back if that did not work
+    crate::acpi::power::power_off();
+
+    x86_64::instructions::interrupts::disable();
+    let hypervisor = raw_cpuid::CpuId::new().get_feature_info().is_some_and(|f| f.has_hypervisor());
+    if hypervisor {
+        for (port, value) in SHUTDOWN_PORTS {
+            unsafe { Port::<u16>::new(port).write(value) };
         }
     }
+
+    crate::println!("shutdown failed, it is now safe to turn off your computer");
+    crate::hlt_loop();
 }
 
 pub fn get_os_version() -> &'static str {
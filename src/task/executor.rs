@@ -13,6 +13,7 @@ use core::task::{
     Waker,
 };
 use crossbeam_queue::ArrayQueue;
+use crate::time::Instant;
 
 
 
@@ -123,11 +124,18 @@ impl Executor {
             // create a context from the waker for polling
             let mut context = Context::from_waker(waker);
 
-            match task.poll(&mut context) {
+            super::set_current(Some(task_id));
+            let start = Instant::now();
+            let poll = task.poll(&mut context);
+            super::record_poll(task_id, start.elapsed());
+            super::set_current(None);
+
+            match poll {
                 Poll::Ready(()) => {
                     // task completed -> remove it and its cached waker
                     tasks.remove(&task_id);
                     waker_cache.remove(&task_id);
+                    super::forget_polls(task_id);
                 }
                 Poll::Pending => {
                     // task is not ready yet, it will be re-queued by its waker
@@ -1,7 +1,9 @@
 use core::{future::Future, pin::Pin};
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
 use core::task::{Context, Poll};
 use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use crate::time::Duration;
 
 pub mod keyboard;
 pub mod executor;
@@ -11,6 +13,56 @@ pub mod shell;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct TaskId(u64);
 
+// id of the task the executor is polling, NO_TASK in between
+static CURRENT: AtomicU64 = AtomicU64::new(NO_TASK);
+const NO_TASK: u64 = u64::MAX;
+
+/// Id of the task being polled right now, `None` outside of tasks.
+pub fn current() -> Option<u64> {
+    match CURRENT.load(Ordering::Relaxed) {
+        NO_TASK => None,
+        id => Some(id),
+    }
+}
+
+fn set_current(task: Option<TaskId>) {
+    CURRENT.store(task.map_or(NO_TASK, |id| id.0), Ordering::Relaxed);
+}
+
+/// How long the executor spent polling a task.
+#[derive(Debug, Clone, Copy)]
+pub struct PollStats {
+    pub id: u64,
+    pub polls: u64,
+    pub total: Duration,
+    pub longest: Duration,
+}
+
+// poll times of the live tasks, by id
+static POLL_STATS: Mutex<BTreeMap<u64, PollStats>> = Mutex::new(BTreeMap::new());
+
+/// Poll times of the tasks that haven't finished, by id.
+pub fn poll_stats() -> Vec<PollStats> {
+    POLL_STATS.lock().values().copied().collect()
+}
+
+fn record_poll(task: TaskId, took: Duration) {
+    let mut stats = POLL_STATS.lock();
+    let stats = stats.entry(task.0).or_insert(PollStats {
+        id: task.0,
+        polls: 0,
+        total: Duration::ZERO,
+        longest: Duration::ZERO,
+    });
+    stats.polls += 1;
+    stats.total += took;
+    stats.longest = stats.longest.max(took);
+}
+
+fn forget_polls(task: TaskId) {
+    POLL_STATS.lock().remove(&task.0);
+}
+
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
@@ -1,8 +1,10 @@
 use crate::{
     Task, 
     allocator, 
+    cmdline, 
     print, 
     println, 
+    time::Instant,
     timer, 
     task::{
         executor::Executor,
@@ -16,6 +18,7 @@ use crate::{
 use alloc::{
     format, 
     string::*, 
+    vec::Vec,
 };
 use raw_cpuid::CpuId;
 
@@ -111,8 +114,20 @@ enum Command {
     Fetch,
     Clear,
     HeapTest,
+    HeapStats,
+    HeapTop,
+    HeapMark,
+    HeapGrowth,
+    HeapCheck,
+    VmTest,
+    MemMap,
+    Frames,
+    PageWalk(String),
+    Acpi,
+    Tasks,
     Crash,
     Reboot,
+    Shutdown,
     Help,
     Unknown,
 }
@@ -124,7 +139,16 @@ mod commands {
     use crate::{vgaclear, system, println};
     use crate::task::shell::{get_stats, print_fetch, print_header};
     use crate::printcolor;
-    use crate::allocator;
+    use crate::allocator::{self, guard, track};
+    use crate::print;
+    use crate::memory::{self, layout, vm::{self, Backing, Protection}};
+    use crate::memory::frame::{FRAME_ALLOCATOR, MAX_ORDER, Zone};
+    use crate::multiboot;
+    use crate::task;
+    use crate::time::Duration;
+    use crate::acpi::{self, fadt, hpet, madt, mcfg};
+    use crate::bootinfo::MemoryRegionKind;
+    use x86_64::VirtAddr;
     use alloc::vec::Vec;
     use alloc::boxed::Box;
 
@@ -156,6 +180,248 @@ mod commands {
         println!("Done!");
     }
 
+    pub fn heapstats() {
+        println!("heap: {}", allocator::heap_stat());
+        println!("size  in use    free  slabs     allocs      frees");
+        for cache in allocator::slab_stats() {
+            println!(
+                "{:>4} {:>7} {:>7} {:>6} {:>10} {:>10}",
+                cache.size, cache.in_use, cache.free, cache.slabs, cache.allocs, cache.frees
+            );
+        }
+    }
+
+    // heap tracking needs `heap_track=on`
+    fn tracking_enabled() -> bool {
+        if !track::enabled() {
+            println!("heap tracking is off, boot with heap_track=on");
+        }
+        track::enabled()
+    }
+
+    pub fn heaptop() {
+        if !tracking_enabled() {
+            return;
+        }
+        if let Some(stats) = track::stats() {
+            println!("{} live allocations, {} bytes, {} not recorded", stats.live, stats.bytes, stats.dropped);
+        }
+
+        let mut largest = [track::Allocation::EMPTY; 10];
+        let count = track::largest(&mut largest);
+        for allocation in &largest[..count] {
+            print!("{:#x} {:>8} bytes  task {:?}  from", allocation.addr, allocation.size, allocation.task);
+            for caller in allocation.callers.iter().filter(|&&c| c != 0) {
+                print!(" {:#x}", caller);
+            }
+            println!();
+        }
+    }
+
+    pub fn heapmark() {
+        if tracking_enabled() {
+            track::mark();
+            println!("checkpoint set, see 'heap growth'");
+        }
+    }
+
+    pub fn heapgrowth() {
+        if !tracking_enabled() {
+            return;
+        }
+
+        let mut growth = [track::Growth::EMPTY; 10];
+        let count = track::growth(&mut growth);
+        if count == 0 {
+            println!("nothing allocated since the checkpoint is still live");
+        }
+        for group in &growth[..count] {
+            print!("{:>5} allocations {:>8} bytes  task {:?}  from", group.count, group.bytes, group.task);
+            for caller in group.callers.iter().filter(|&&c| c != 0) {
+                print!(" {:#x}", caller);
+            }
+            println!();
+        }
+    }
+
+    pub fn heapcheck() {
+        if !guard::enabled() {
+            println!("heap debugging is off, boot with heap_debug=on");
+            return;
+        }
+        let report = allocator::heap_check();
+        println!(
+            "checked {} live and {} freed allocations, {} corrupted",
+            report.live, report.quarantined, report.corrupted
+        );
+    }
+
+    pub fn vmtest() {
+        const SIZE: u64 = 1024 * 1024;
+        const STRIDE: usize = 64 * 1024;
+
+        let area = vm::allocate(layout::VM, SIZE, 4096, Protection::READ_WRITE, Backing::Anonymous, "vm test");
+        let start = match area {
+            Ok(start) => start,
+            Err(err) => {
+                println!("vm test: {:?}", err);
+                return;
+            }
+        };
+        println!("reserved {} KiB at {:?}, nothing mapped yet", SIZE / 1024, start);
+
+        // every touch faults a page in
+        let area = start.as_mut_ptr::<u8>();
+        for offset in (0..SIZE as usize).step_by(STRIDE) {
+            unsafe { area.add(offset).write_volatile(offset as u8) };
+        }
+        if let Some(vma) = vm::find(start) {
+            printcolor!(Color::LightGreen, Color::Black, "Test done: {} of {} pages resident\n", vma.resident, SIZE / 4096);
+        }
+
+        unsafe { vm::unmap(start) }.expect("vm test: unmap failed");
+        println!("Done!");
+    }
+
+    pub fn memmap() {
+        let Some(boot_info) = multiboot::boot_info() else {
+            println!("no boot info");
+            return;
+        };
+
+        let (mut usable, mut bootloader, mut other) = (0, 0, 0);
+        println!("physical memory:");
+        for region in boot_info.memory_regions.iter() {
+            let size = region.end - region.start;
+            println!("    {:#012x} - {:#012x} {:>8} KiB  {:?}", region.start, region.end, size / 1024, region.kind);
+            match region.kind {
+                MemoryRegionKind::Usable => usable += size,
+                MemoryRegionKind::Bootloader => bootloader += size,
+                _ => other += size,
+            }
+        }
+        println!(
+            "usable {} MiB, kernel and boot data {} KiB, reserved {} KiB",
+            usable >> 20, bootloader >> 10, other >> 10
+        );
+
+        println!("vm areas:");
+        vm::areas(|vma| {
+            println!(
+                "    {:#x} - {:#x} {:>6}/{} pages  {:?} {:?}  {}",
+                vma.start, vma.end(), vma.resident, vma.size / 4096, vma.protection, vma.backing, vma.name
+            );
+        });
+    }
+
+    pub fn frames() {
+        let mut frames = FRAME_ALLOCATOR.lock();
+        let (total, used, free) = (frames.total_frames(), frames.used_frames(), frames.free_frames());
+        let free_dma = frames.free_frames_in(Zone::Dma);
+        let free_dma32 = frames.free_frames_in(Zone::Dma32);
+        let mut blocks = [0; MAX_ORDER + 1];
+        for (order, count) in blocks.iter_mut().enumerate() {
+            *count = frames.free_blocks(order);
+        }
+        drop(frames);
+
+        println!("frames: {} used, {} free of {} ({} MiB free)", used, free, total, free * 4 / 1024);
+        println!("free below 16 MiB: {}, below 4 GiB: {}", free_dma, free_dma32);
+        println!("free blocks by order:");
+        for (order, count) in blocks.iter().enumerate() {
+            println!("    {:>2} ({:>4} KiB): {}", order, 4 << order, count);
+        }
+    }
+
+    pub fn pagewalk(arg: &str) {
+        let digits = arg.trim().trim_start_matches("0x");
+        let addr = u64::from_str_radix(digits, 16)
+            .ok()
+            .and_then(|addr| VirtAddr::try_new(addr).ok());
+        let Some(addr) = addr else {
+            println!("usage: pagewalk <hex address>");
+            return;
+        };
+
+        let phys = memory::page_walk(addr, |step| {
+            println!(
+                "    L{}[{:>3}] in {:#x}: {:#x} {:?}",
+                step.level, step.index, step.table, step.addr, step.flags
+            );
+        });
+        match phys {
+            Some(phys) => println!("{:?} -> {:?}", addr, phys),
+            None => println!("{:?} is not mapped", addr),
+        }
+    }
+
+    pub fn acpi() {
+        let (Some(rsdp), Some(root)) = (acpi::rsdp(), acpi::root()) else {
+            println!("no acpi tables found");
+            return;
+        };
+        println!("rsdp at {:#x}, revision {}, oem {}", rsdp.phys, rsdp.revision, rsdp.oem_id());
+
+        let tables = core::iter::once(*root).chain(acpi::tables()).chain(acpi::dsdt());
+        for table in tables {
+            println!(
+                "    {} at {:#010x} {:>6} bytes  rev {}  {} {} {}",
+                table.signature(), table.phys, table.len(), table.revision(),
+                table.oem_id(), table.oem_table_id(), table.oem_revision()
+            );
+        }
+
+        if let Some(madt) = madt::get() {
+            let (mut cpus, mut ioapics, mut overrides) = (0, 0, 0);
+            for entry in madt.entries() {
+                match entry {
+                    madt::Entry::LocalApic { enabled: true, .. } => cpus += 1,
+                    madt::Entry::IoApic { .. } => ioapics += 1,
+                    madt::Entry::InterruptOverride(_) => overrides += 1,
+                    _ => {}
+                }
+            }
+            println!(
+                "madt: local apics at {:#x}, {} cpus, {} io apics, {} irq overrides{}",
+                madt.local_apic_address, cpus, ioapics, overrides,
+                if madt.pcat_compat { ", 8259 pics" } else { "" }
+            );
+        }
+        if let Some(fadt) = fadt::get() {
+            println!(
+                "fadt: sci irq {}, pm1a control {:#x}, pm timer {:#x}, reset {}",
+                fadt.sci_interrupt, fadt.pm1a_control, fadt.pm_timer,
+                if fadt.reset_register.is_some() { "supported" } else { "unsupported" }
+            );
+        }
+        if let Some(hpet) = hpet::get() {
+            println!(
+                "hpet: {:#x}, {} comparators, {} bit counter, minimum tick {}",
+                hpet.base_address.address, hpet.comparators,
+                if hpet.counter_64bit { 64 } else { 32 }, hpet.minimum_tick
+            );
+        }
+        if let Some(mcfg) = mcfg::get() {
+            for allocation in mcfg.allocations() {
+                println!(
+                    "mcfg: segment {} buses {}-{} at {:#x}",
+                    allocation.segment, allocation.start_bus, allocation.end_bus, allocation.base
+                );
+            }
+        }
+    }
+
+    pub fn tasks() {
+        println!("task  polls      total    longest     average");
+        for stats in task::poll_stats() {
+            println!(
+                "{:>4} {:>6} {:>10?} {:>10?} {:>11?}",
+                stats.id, stats.polls, stats.total, stats.longest,
+                Duration::from_nanos((stats.total.as_nanos() / stats.polls.max(1) as u128) as u64)
+            );
+        }
+    }
+
     pub fn crash() {
         let mut count = 0;
         loop {
@@ -171,13 +437,30 @@ mod commands {
         system::reboot();
     }
 
+    pub fn shutdown() {
+        system::shutdown();
+    }
+
     pub fn help() {
         set_print_color(Color::Yellow, Color::Black);
         println!("    fetch");
         println!("    clear");
         println!("    heap test");
+        println!("    heap stats");
+        println!("    heap top");
+        println!("    heap mark");
+        println!("    heap growth");
+        println!("    heap check");
+        println!("    vm test");
+        println!("    memmap");
+        println!("    frames");
+        println!("    pagewalk <addr>");
+        println!("    acpi");
+        println!("    tasks");
         println!("    crash");
         println!("    reboot");
+        println!("    shutdown");
+        println!("    time <command>");
         set_print_color(Color::White, Color::Black);
     }
 
@@ -191,12 +474,31 @@ mod commands {
 
 
 fn parse_input(input: &String) -> Command {
-    match input.trim().to_lowercase().as_str() {
+    let input = input.trim().to_lowercase();
+
+    // commands with an argument
+    let mut words = input.split_whitespace();
+    if words.next() == Some("pagewalk") {
+        return Command::PageWalk(words.collect::<Vec<_>>().join(" "));
+    }
+
+    match input.as_str() {
         "fetch"     => Command::Fetch,
         "clear"     => Command::Clear,
         "heap test" => Command::HeapTest,
+        "heap stats" => Command::HeapStats,
+        "heap top" => Command::HeapTop,
+        "heap mark" => Command::HeapMark,
+        "heap growth" => Command::HeapGrowth,
+        "heap check" => Command::HeapCheck,
+        "vm test"   => Command::VmTest,
+        "memmap"    => Command::MemMap,
+        "frames"    => Command::Frames,
+        "acpi"      => Command::Acpi,
+        "tasks"     => Command::Tasks,
         "crash"     => Command::Crash,
         "reboot"    => Command::Reboot,
+        "shutdown"  => Command::Shutdown,
         "help"      => Command::Help,
         _           => Command::Unknown,
     }
@@ -207,6 +509,13 @@ async fn shell_task() {
     loop {
         print!("kosmos> ");
         let input = get_line().await;
+
+        // `time <command>` runs the command and says how long it took
+        let (input, timed) = match input.trim_start().strip_prefix("time ") {
+            Some(command) => (command.to_string(), true),
+            None => (input, false),
+        };
+        let start = Instant::now();
         let cmd = parse_input(&input);
         
        
@@ -214,14 +523,34 @@ async fn shell_task() {
             Command::Fetch      => commands::fetch(),
             Command::Clear      => commands::clear(),
             Command::HeapTest   => commands::heaptest(),
+            Command::HeapStats  => commands::heapstats(),
+            Command::HeapTop    => commands::heaptop(),
+            Command::HeapMark   => commands::heapmark(),
+            Command::HeapGrowth => commands::heapgrowth(),
+            Command::HeapCheck  => commands::heapcheck(),
+            Command::VmTest     => commands::vmtest(),
+            Command::MemMap     => commands::memmap(),
+            Command::Frames     => commands::frames(),
+            Command::PageWalk(arg) => commands::pagewalk(&arg),
+            Command::Acpi       => commands::acpi(),
+            Command::Tasks      => commands::tasks(),
             Command::Crash      => commands::crash(),
             Command::Reboot     => commands::reboot(),
+            Command::Shutdown   => commands::shutdown(),
             Command::Help       => commands::help(),
             Command::Unknown    => commands::unknown_command(&input.as_str()),
         }
+        if timed {
+            println!("took {:?}", start.elapsed());
+        }
     }
 }
 
+// `shell=off` on the command line boots without a shell
 pub fn spawn_shell(executor: &mut Executor) {
+    if cmdline::flag("shell") == Some(false) {
+        println!("shell disabled on the command line");
+        return;
+    }
     executor.spawn(Task::new(shell_task()))
 }
\ No newline at end of file
@@ -0,0 +1,152 @@
+// monotonic time
+//
+// `Instant` is a point in time with nanosecond resolution, counted from when
+// its clock started. the clocks, best first:
+//
+//   tsc     an invariant time stamp counter, see `timer::tsc`
+//   hpet    the hpet main counter, see `timer::hpet`
+//   tsc     a time stamp counter that is not invariant
+//   ticks   the pit tick count since boot, as coarse as the tick rate
+//
+// the clock never changes after `init`, so instants always compare.
+
+use core::{
+    ops::{Add, AddAssign, Sub, SubAssign},
+    sync::atomic::{AtomicU8, Ordering},
+};
+use crate::{interrupts::TIMER_TICKS, timer::{self, hpet, tsc}};
+
+pub use core::time::Duration;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Where `Instant::now` gets the time from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Clock {
+    Ticks,
+    Tsc,
+    Hpet,
+}
+
+impl Clock {
+    pub fn name(self) -> &'static str {
+        match self {
+            Clock::Ticks => "pit ticks",
+            Clock::Tsc => "tsc",
+            Clock::Hpet => "hpet",
+        }
+    }
+}
+
+static CLOCK: AtomicU8 = AtomicU8::new(Clock::Ticks as u8);
+
+/// A point in monotonic time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(now_nanos())
+    }
+
+    /// Time passed since `earlier`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos()).ok().and_then(|nanos| self.0.checked_add(nanos)).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        u64::try_from(duration.as_nanos()).ok().and_then(|nanos| self.0.checked_sub(nanos)).map(Instant)
+    }
+
+    /// Nanoseconds since the clock started.
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("time: overflow adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("time: overflow subtracting a duration from an instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Picks the best clock there is. Call after `acpi::init`, with the pit set
+/// up.
+pub fn init() -> Clock {
+    // the hpet first, the tsc rate may be measured against it
+    let hpet = hpet::init();
+    let tsc = tsc::init().is_some();
+
+    let clock = match (tsc, hpet) {
+        (true, _) if tsc::invariant() => Clock::Tsc,
+        (_, true) => Clock::Hpet,
+        (true, false) => Clock::Tsc,
+        (false, false) => Clock::Ticks,
+    };
+    CLOCK.store(clock as u8, Ordering::Relaxed);
+    clock
+}
+
+/// The clock `Instant::now` reads.
+pub fn clock() -> Clock {
+    match CLOCK.load(Ordering::Relaxed) {
+        clock if clock == Clock::Hpet as u8 => Clock::Hpet,
+        clock if clock == Clock::Tsc as u8 => Clock::Tsc,
+        _ => Clock::Ticks,
+    }
+}
+
+/// Time since the clock started.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(now_nanos())
+}
+
+fn now_nanos() -> u64 {
+    match clock() {
+        Clock::Hpet => hpet::get().map_or(0, |hpet| hpet.nanos()),
+        Clock::Tsc => tsc::nanos(),
+        Clock::Ticks => {
+            let ticks = TIMER_TICKS.load(Ordering::Relaxed) as u128;
+            (ticks * NANOS_PER_SEC as u128 / timer::frequency() as u128) as u64
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// high precision event timer
+//
+// the hpet has a free running main counter ticking at a fixed rate of at
+// least 10 MHz, which makes it a good monotonic clock. we only use the
+// counter, not the comparators. some hpets have a 32 bit counter, which
+// wraps every few minutes: reads are extended to 64 bits by adding how far
+// the counter moved since the last read, which works as long as it is read
+// at least every half wrap.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use conquer_once::spin::OnceCell;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::{acpi::{self, AddressSpace}, memory::mmio};
+
+// registers, as byte offsets
+const CAPABILITIES: u64 = 0x000;
+const CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+const REGISTERS_SIZE: usize = 0x400;
+
+// capabilities: counter size and the tick period in the upper half
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const PERIOD_SHIFT: u64 = 32;
+
+// configuration: start the main counter
+const ENABLE_CNF: u64 = 1 << 0;
+
+// the spec says the period is at most 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+// last counter value read, for extending a 32 bit counter
+static LAST: AtomicU64 = AtomicU64::new(0);
+
+/// An enabled hpet.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub phys: PhysAddr,
+    /// Femtoseconds per tick.
+    pub period: u64,
+    pub counter_64bit: bool,
+    regs: VirtAddr,
+}
+
+impl Hpet {
+    fn read(&self, reg: u64) -> u64 {
+        unsafe { ptr::read_volatile((self.regs + reg).as_ptr::<u64>()) }
+    }
+
+    fn write(&self, reg: u64, value: u64) {
+        unsafe { ptr::write_volatile((self.regs + reg).as_mut_ptr::<u64>(), value) }
+    }
+
+    /// Ticks per second.
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period
+    }
+
+    /// The main counter, extended to 64 bits if it is only 32 wide.
+    pub fn counter(&self) -> u64 {
+        if self.counter_64bit {
+            return self.read(MAIN_COUNTER);
+        }
+
+        let low = self.read(MAIN_COUNTER) as u32;
+        let mut last = LAST.load(Ordering::Relaxed);
+        loop {
+            // how far the counter moved since `last`, across a wrap too. a
+            // negative distance means `last` was stored by a newer read, an
+            // interrupt handler that came in after ours, and is the answer
+            let ahead = low.wrapping_sub(last as u32) as i32;
+            if ahead < 0 {
+                return last;
+            }
+            let now = last + ahead as u64;
+            match LAST.compare_exchange_weak(last, now, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return now,
+                Err(newer) => last = newer,
+            }
+        }
+    }
+
+    /// Nanoseconds the counter has run for.
+    pub fn nanos(&self) -> u64 {
+        (self.counter() as u128 * self.period as u128 / FEMTOS_PER_NANO as u128) as u64
+    }
+}
+
+/// Finds the hpet through acpi, maps it and starts its counter from 0.
+/// Returns `false` if there is no usable hpet.
+///
+/// Call after `acpi::init`.
+pub fn init() -> bool {
+    let Some(table) = acpi::hpet::get() else {
+        return false;
+    };
+    if table.base_address.space != AddressSpace::SystemMemory {
+        return false;
+    }
+
+    let phys = PhysAddr::new(table.base_address.address);
+    let Ok(mapping) = mmio::map_mmio(phys, REGISTERS_SIZE) else {
+        return false;
+    };
+    let regs = VirtAddr::from_ptr(mapping.as_ptr());
+
+    let mut hpet = Hpet { phys, period: 0, counter_64bit: false, regs };
+    let capabilities = hpet.read(CAPABILITIES);
+    hpet.period = capabilities >> PERIOD_SHIFT;
+    hpet.counter_64bit = capabilities & COUNT_SIZE_CAP != 0;
+    if hpet.period == 0 || hpet.period > MAX_PERIOD_FS {
+        let _ = unsafe { mmio::unmap_mmio(mapping) };
+        return false;
+    }
+
+    // the counter may only be written while it is stopped
+    let config = hpet.read(CONFIGURATION);
+    hpet.write(CONFIGURATION, config & !ENABLE_CNF);
+    hpet.write(MAIN_COUNTER, 0);
+    hpet.write(CONFIGURATION, config | ENABLE_CNF);
+
+    HPET.try_init_once(|| hpet).is_ok()
+}
+
+/// The hpet, once `init` has enabled it.
+pub fn get() -> Option<&'static Hpet> {
+    HPET.get()
+}
@@ -0,0 +1,61 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::{cmdline, interrupts::TIMER_TICKS};
+
+pub mod hpet; // high precision event timer
+pub mod tsc; // time stamp counter
+
+pub const DEFAULT_TIMER_HZ: u64 = 100;
+
+// PIT input clock
+const PIT_FREQUENCY: u64 = 1193182;
+
+// tick rate the PIT was programmed with
+static TIMER_HZ: AtomicU64 = AtomicU64::new(DEFAULT_TIMER_HZ);
+
+// initialize the PIT
+//
+// call once during kernel init, before enabling interrupts.
+// the tick rate can be changed with `timer_hz=` on the command line.
+pub fn init() {
+    // the divisor has to fit in 16 bits
+    let hz = cmdline::number("timer_hz")
+        .unwrap_or(DEFAULT_TIMER_HZ)
+        .clamp(PIT_FREQUENCY / 0xFFFF + 1, PIT_FREQUENCY);
+    TIMER_HZ.store(hz, Ordering::Relaxed);
+
+    let divisor: u16 = (PIT_FREQUENCY / hz) as u16;
+
+    unsafe {
+        let mut cmd = Port::new(0x43);
+        let mut data = Port::new(0x40);
+
+        // channel 0, access low/high byte, mode 3 (square wave)
+        cmd.write(0x36 as u8);
+        data.write((divisor & 0xFF) as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+// called from the timer interrupt handler
+#[inline]
+pub fn tick() {
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+
+    // a 32 bit hpet counter has to be read at least every half wrap
+    if let Some(hpet) = hpet::get().filter(|hpet| !hpet.counter_64bit) {
+        hpet.counter();
+    }
+}
+
+// timer ticks per second
+#[inline]
+pub fn frequency() -> u64 {
+    TIMER_HZ.load(Ordering::Relaxed)
+}
+
+// uptime in seconds since boot
+#[inline]
+pub fn uptime_seconds() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed) / frequency()
+}
\ No newline at end of file
@@ -0,0 +1,192 @@
+// time stamp counter
+//
+// the tsc counts at a fixed rate and is the cheapest clock there is: one
+// instruction, no port or mmio access, so it can be read in interrupt
+// handlers. on older cpus the rate changes with the cpu frequency and the
+// counter stops in deep sleep states. cpus with an invariant tsc don't do
+// that, which makes it a good clocksource.
+//
+// the rate comes from, in order:
+//
+//   cpuid      leaf 0x15 (tsc to crystal ratio) or 0x16 (base frequency)
+//   hypervisor the hypervisor timing leaf 0x40000010
+//   hpet       measured against the hpet counter
+//   pit        measured against pit channel 2, which can be started and
+//              watched without interrupts through port 0x61
+
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering},
+};
+use raw_cpuid::{CpuId, CpuIdReaderNative};
+use x86_64::instructions::port::Port;
+use super::{PIT_FREQUENCY, hpet};
+
+// pit channel 2 gate and output, in the system control port
+const SYSTEM_CONTROL: u16 = 0x61;
+const GATE: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUTPUT: u8 = 1 << 5;
+
+// channel 2, low then high byte, mode 0 (interrupt on terminal count)
+const PIT_COMMAND: u16 = 0x43;
+const PIT_CHANNEL_2: u16 = 0x42;
+const CHANNEL_2_ONE_SHOT: u8 = 0xB0;
+
+// how long to measure for
+const CALIBRATION_MS: u64 = 10;
+
+// give up on a timer that never gets there
+const MAX_POLLS: usize = 10_000_000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Where the tsc rate came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Source {
+    Cpuid = 1,
+    Hypervisor,
+    Hpet,
+    Pit,
+}
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Cpuid => "cpuid",
+            Source::Hypervisor => "hypervisor",
+            Source::Hpet => "hpet",
+            Source::Pit => "pit",
+        }
+    }
+}
+
+// tsc rate in Hz, 0 until `init` found it
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static SOURCE: AtomicU8 = AtomicU8::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+// tsc value at `init`, and nanoseconds per tick as a 32.32 fixed point number
+static BASE: AtomicU64 = AtomicU64::new(0);
+static NANOS_PER_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the time stamp counter.
+#[inline]
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Tsc ticks per second, once known.
+pub fn frequency() -> Option<u64> {
+    Some(FREQUENCY.load(Ordering::Relaxed)).filter(|&hz| hz != 0)
+}
+
+/// Where the rate came from, once known.
+pub fn source() -> Option<Source> {
+    match SOURCE.load(Ordering::Relaxed) {
+        source if source == Source::Cpuid as u8 => Some(Source::Cpuid),
+        source if source == Source::Hypervisor as u8 => Some(Source::Hypervisor),
+        source if source == Source::Hpet as u8 => Some(Source::Hpet),
+        source if source == Source::Pit as u8 => Some(Source::Pit),
+        _ => None,
+    }
+}
+
+/// Whether the tsc keeps a constant rate through frequency changes and
+/// sleep states.
+pub fn invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since `init`, 0 before. Only a multiply and a shift on top
+/// of `rdtsc`.
+#[inline]
+pub fn nanos() -> u64 {
+    let ticks = read().saturating_sub(BASE.load(Ordering::Relaxed));
+    ((ticks as u128 * NANOS_PER_TICK.load(Ordering::Relaxed) as u128) >> 32) as u64
+}
+
+/// Finds the tsc rate and starts `nanos` from 0. Returns `None` if there is
+/// no tsc or its rate could not be found.
+///
+/// Call after `hpet::init`, so the hpet can be used to measure it.
+pub fn init() -> Option<u64> {
+    let cpuid = CpuId::new();
+    if !cpuid.get_feature_info().is_some_and(|f| f.has_tsc()) {
+        return None;
+    }
+    let invariant = cpuid.get_advanced_power_mgmt_info().is_some_and(|apm| apm.has_invariant_tsc());
+
+    let (hz, source) = from_cpuid(&cpuid)
+        .map(|hz| (hz, Source::Cpuid))
+        .or_else(|| from_hypervisor(&cpuid).map(|hz| (hz, Source::Hypervisor)))
+        .or_else(|| hpet::get().and_then(calibrate_with_hpet).map(|hz| (hz, Source::Hpet)))
+        .or_else(|| calibrate_with_pit().map(|hz| (hz, Source::Pit)))?;
+
+    INVARIANT.store(invariant, Ordering::Relaxed);
+    SOURCE.store(source as u8, Ordering::Relaxed);
+    FREQUENCY.store(hz, Ordering::Relaxed);
+    NANOS_PER_TICK.store((((NANOS_PER_SEC as u128) << 32) / hz as u128) as u64, Ordering::Relaxed);
+    BASE.store(read(), Ordering::Relaxed);
+    Some(hz)
+}
+
+// leaf 0x15 gives the exact rate when it names the crystal frequency, leaf
+// 0x16 the base frequency the tsc runs at
+fn from_cpuid(cpuid: &CpuId<CpuIdReaderNative>) -> Option<u64> {
+    cpuid
+        .get_tsc_info()
+        .and_then(|info| info.tsc_frequency())
+        .or_else(|| {
+            let mhz = cpuid.get_processor_frequency_info()?.processor_base_frequency();
+            (mhz != 0).then_some(mhz as u64 * 1_000_000)
+        })
+}
+
+// some hypervisors tell the guest the tsc rate in kHz
+fn from_hypervisor(cpuid: &CpuId<CpuIdReaderNative>) -> Option<u64> {
+    let khz = cpuid.get_hypervisor_info()?.tsc_frequency()?;
+    (khz != 0).then_some(khz as u64 * 1000)
+}
+
+// counts tsc ticks while the hpet counts CALIBRATION_MS
+fn calibrate_with_hpet(hpet: &hpet::Hpet) -> Option<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let ticks = hpet.frequency() * CALIBRATION_MS / 1000;
+        let (hpet_start, start) = (hpet.counter(), read());
+        let done = (0..MAX_POLLS).any(|_| hpet.counter() - hpet_start >= ticks);
+        let (hpet_end, end) = (hpet.counter(), read());
+
+        // scale by what the hpet really counted, it overshoots a little
+        let elapsed = hpet_end - hpet_start;
+        done.then(|| ((end - start) as u128 * hpet.frequency() as u128 / elapsed as u128) as u64)
+    })
+}
+
+// counts tsc ticks while pit channel 2 counts down CALIBRATION_MS
+fn calibrate_with_pit() -> Option<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let latch = (PIT_FREQUENCY * CALIBRATION_MS / 1000) as u16;
+        let mut control = Port::<u8>::new(SYSTEM_CONTROL);
+
+        let original = unsafe { control.read() };
+
+        let start = unsafe {
+            // gate on, speaker off, then load the count which starts it
+            control.write((original & !SPEAKER) | GATE);
+            Port::<u8>::new(PIT_COMMAND).write(CHANNEL_2_ONE_SHOT);
+            let mut channel = Port::<u8>::new(PIT_CHANNEL_2);
+            channel.write(latch as u8);
+            channel.write((latch >> 8) as u8);
+            read()
+        };
+
+        // the output goes high when the count reaches 0
+        let done = (0..MAX_POLLS).any(|_| unsafe { control.read() } & OUTPUT != 0);
+        let end = read();
+        unsafe { control.write(original) };
+
+        done.then(|| (end - start) * 1000 / CALIBRATION_MS)
+    })
+}
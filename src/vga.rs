@@ -1,8 +1,9 @@
 use core::fmt::{Write, Result, self};
+use core::sync::atomic::{AtomicBool, Ordering};
 use volatile::Volatile;
 use lazy_static::lazy_static;
-use spin::Mutex;
-use x86_64::instructions::port::Port;
+use spin::{Mutex, MutexGuard};
+use x86_64::{PhysAddr, instructions::port::Port};
 
 // VGA color values
 #[allow(dead_code)]
@@ -201,11 +202,62 @@ lazy_static! {
         column_position: 0,
         row_position: 0,
         color_code: ColorCode::new(Color::White, Color::Black),
-        // VGA text buffer lives at 0xb8000
-        buffer: unsafe { &mut *(0xB8000 as *mut Buffer) },
+        // VGA text buffer lives at physical 0xb8000, `remap` moves it to
+        // an uncached mapping once memory is set up
+        buffer: unsafe { &mut *((crate::memory::PHYS_OFFSET + 0xB8000) as *mut Buffer) },
     });
 }
 
+/// Moves the writer from the physical map to an uncached mmio mapping of the
+/// text buffer. Call once the memory subsystem is up.
+pub fn remap() {
+    let buffer = crate::memory::mmio::map_mmio(PhysAddr::new(0xB8000), size_of::<Buffer>())
+        .expect("vga: failed to map the text buffer");
+    WRITER.lock().buffer = unsafe { &mut *(buffer.as_mut_ptr() as *mut Buffer) };
+}
+
+// set once the kernel is going down, see `force_output`
+static FORCE_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Lets `eprint!` take the output locks by force from now on. Only for
+/// crash reports that end in a halt: the code holding the locks may be what
+/// crashed, and it never runs again.
+pub fn force_output() {
+    FORCE_OUTPUT.store(true, Ordering::Relaxed);
+}
+
+// prints to both the screen and the serial port, whatever `console=` says.
+// a lock that is held drops the output, since its holder may be the code we
+// interrupted and will carry on writing once we return. after
+// `force_output` the locks are taken by force instead.
+#[doc(hidden)]
+pub fn _eprint(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+    use crate::serial::SERIAL1;
+
+    let force = FORCE_OUTPUT.load(Ordering::Relaxed);
+    interrupts::without_interrupts(|| {
+        if let Some(mut writer) = output_lock(&WRITER, force) {
+            let _ = writer.write_fmt(args);
+        }
+        if let Some(mut serial) = output_lock(&SERIAL1, force) {
+            let _ = serial.write_fmt(args);
+        }
+    });
+}
+
+fn output_lock<T>(mutex: &'static Mutex<T>, force: bool) -> Option<MutexGuard<'static, T>> {
+    if let Some(guard) = mutex.try_lock() {
+        return Some(guard);
+    }
+    if !force {
+        return None;
+    }
+    unsafe { mutex.force_unlock() };
+    Some(mutex.lock())
+}
+
 // print without a newline
 #[macro_export]
 macro_rules! print {
@@ -219,6 +271,18 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// print to the screen and the serial port, for crash reports
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::vga::_eprint(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
+
 #[macro_export]
 macro_rules! vgaclear {
     () => ($crate::vga::_clear());
@@ -252,12 +316,22 @@ pub fn _clear() {
 
 // Prints the given formatted string to the VGA text buffer
 // through the global 'WRITER' instance.
+//
+// `console=serial` or `console=both` on the command line sends the output to
+// the serial port instead of, or as well as, the screen.
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
+    use crate::cmdline::{self, Console};
 
+    let console = cmdline::console();
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        if console != Console::Serial {
+            WRITER.lock().write_fmt(args).unwrap();
+        }
+        if console != Console::Vga {
+            crate::serial::_print(args);
+        }
     });
 }
\ No newline at end of file